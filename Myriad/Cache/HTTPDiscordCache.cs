@@ -18,6 +18,7 @@ public class HttpDiscordCache: IDiscordCache
     private readonly string? _eventTarget;
     private readonly int _shardCount;
     private readonly ulong _ownUserId;
+    private readonly string? _internalAuth;
 
     private readonly MemoryDiscordCache _innerCache;
 
@@ -25,7 +26,7 @@ public class HttpDiscordCache: IDiscordCache
 
     public EventHandler<(bool?, string)> OnDebug;
 
-    public HttpDiscordCache(ILogger logger, HttpClient client, string cacheEndpoint, string? eventTarget, int shardCount, ulong ownUserId, bool useInnerCache)
+    public HttpDiscordCache(ILogger logger, HttpClient client, string cacheEndpoint, string? eventTarget, int shardCount, ulong ownUserId, bool useInnerCache, string? internalAuth = null)
     {
         _logger = logger;
         _client = client;
@@ -33,6 +34,7 @@ public class HttpDiscordCache: IDiscordCache
         _eventTarget = eventTarget;
         _shardCount = shardCount;
         _ownUserId = ownUserId;
+        _internalAuth = internalAuth;
         _jsonSerializerOptions = new JsonSerializerOptions().ConfigureForMyriad();
         if (useInnerCache) _innerCache = new MemoryDiscordCache(ownUserId);
     }
@@ -50,6 +52,14 @@ public class HttpDiscordCache: IDiscordCache
 
     public ulong GetOwnUser() => _ownUserId;
 
+    private Task<HttpResponseMessage> Send(HttpMethod method, string url, HttpContent? content = null)
+    {
+        var request = new HttpRequestMessage(method, url) { Content = content };
+        if (_internalAuth != null)
+            request.Headers.Add("X-PluralKit-InternalAuth", _internalAuth);
+        return _client.SendAsync(request);
+    }
+
     private async Task<T?> QueryCache<T>(string endpoint, ulong guildId)
     {
         var cluster = _cacheEndpoint;
@@ -57,7 +67,7 @@ public class HttpDiscordCache: IDiscordCache
         if (cluster.Contains("{clusterid}"))
             cluster = cluster.Replace("{clusterid}", $"{(int)(((guildId >> 22) % (ulong)_shardCount) / 16)}");
 
-        var response = await _client.GetAsync($"http://{cluster}{endpoint}");
+        var response = await Send(HttpMethod.Get, $"http://{cluster}{endpoint}");
 
         if (response.StatusCode == HttpStatusCode.NotFound)
             return default;
@@ -85,12 +95,13 @@ public class HttpDiscordCache: IDiscordCache
         if (cluster.Contains("{clusterid}"))
             cluster = cluster.Replace("{clusterid}", $"{(int)(shardId / 16)}");
 
-        var response = await _client.PostAsync(
+        var response = await Send(
+            HttpMethod.Post,
             $"http://{cluster}/await_event",
             new StringContent(JsonSerializer.Serialize(data), Encoding.UTF8)
         );
 
-        if (response.StatusCode != HttpStatusCode.NoContent)
+        if (!response.IsSuccessStatusCode)
             throw new Exception($"failed to await event from gateway: {response.StatusCode}");
     }
 
@@ -102,6 +113,7 @@ public class HttpDiscordCache: IDiscordCache
             user_id = userId,
             target = _eventTarget!,
             timeout = timeout?.TotalSeconds,
+            guild_id = guildId,
         };
 
         await AwaitEvent(guildId, obj);
@@ -116,6 +128,7 @@ public class HttpDiscordCache: IDiscordCache
             target = _eventTarget!,
             timeout = timeout?.TotalSeconds,
             options = options,
+            guild_id = guildId,
         };
 
         await AwaitEvent(guildId, obj);
@@ -128,6 +141,7 @@ public class HttpDiscordCache: IDiscordCache
             id = id,
             target = _eventTarget!,
             timeout = timeout?.TotalSeconds,
+            shard_id = shardId,
         };
 
         await AwaitEventShard(shardId, obj);
@@ -23,11 +23,16 @@ public class BotConfig
 
     public string? HttpCacheUrl { get; set; }
     public bool HttpUseInnerCache { get; set; } = false;
+    public string? HttpCacheInternalAuth { get; set; }
 
     public string? HttpListenerAddr { get; set; }
     public bool DisableGateway { get; set; } = false;
     public string? EventAwaiterTarget { get; set; }
 
+    // read events from this redis stream, for when the gateway publishes to `redis-stream://{key}?group={group}`
+    public string? EventStreamKey { get; set; }
+    public string EventStreamGroup { get; set; } = "bot";
+
     public string? DiscordBaseUrl { get; set; }
     public string? AvatarServiceUrl { get; set; }
 
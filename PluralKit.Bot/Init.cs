@@ -73,6 +73,10 @@ public class Init
             if (config.HttpListenerAddr != null)
                 services.Resolve<HttpListenerService>().Start(config.HttpListenerAddr);
 
+            // Start reading events from the gateway's redis stream
+            if (config.EventStreamKey != null)
+                services.Resolve<RedisStreamListenerService>().Start(config.EventStreamKey, config.EventStreamGroup, ct);
+
             // Start the Discord shards themselves (handlers already set up)
             if (!config.DisableGateway)
             {
@@ -56,7 +56,8 @@ public class BotModule: Module
                     botConfig.EventAwaiterTarget,
                     botConfig.Cluster?.TotalShards ?? 1,
                     botConfig.ClientId,
-                    botConfig.HttpUseInnerCache
+                    botConfig.HttpUseInnerCache,
+                    botConfig.HttpCacheInternalAuth
                 );
 
                 var metrics = c.Resolve<IMetrics>();
@@ -161,6 +162,7 @@ public class BotModule: Module
         builder.RegisterType<InteractionDispatchService>().AsSelf().SingleInstance();
         builder.RegisterType<AvatarHostingService>().AsSelf().SingleInstance();
         builder.RegisterType<HttpListenerService>().AsSelf().SingleInstance();
+        builder.RegisterType<RedisStreamListenerService>().AsSelf().SingleInstance();
         builder.RegisterType<RuntimeConfigService>().AsSelf().SingleInstance();
 
         // Sentry stuff
@@ -76,13 +76,19 @@ public class HttpListenerService
         var shardIdString = ctx.Request.Url.Parameters["shard_id"];
         if (!int.TryParse(shardIdString, out var shardId)) return;
 
-        var packet = JsonSerializer.Deserialize<GatewayPacket>(ctx.Request.DataAsString, _jsonSerializerOptions);
+        await HandleGatewayEvent(shardId, ctx.Request.DataAsString);
+        await ctx.Response.Send("a");
+    }
+
+    // also used for events read from a redis stream, see RedisStreamListenerService
+    public async Task HandleGatewayEvent(int shardId, string rawEvent)
+    {
+        var packet = JsonSerializer.Deserialize<GatewayPacket>(rawEvent, _jsonSerializerOptions);
         var evt = DeserializeEvent(shardId, packet.EventType!, (JsonElement)packet.Payload!);
         if (evt != null)
         {
             await _bot.OnEventReceivedInner(shardId, evt);
         }
-        await ctx.Response.Send("a");
     }
 
     private IGatewayEvent? DeserializeEvent(int shardId, string eventType, JsonElement payload)
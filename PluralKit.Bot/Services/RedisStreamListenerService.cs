@@ -0,0 +1,123 @@
+using Serilog;
+
+using StackExchange.Redis;
+
+using PluralKit.Core;
+
+namespace PluralKit.Bot;
+
+// reads events the gateway publishes to a redis stream, as one consumer in a group shared by all bot workers
+// entries are only acked once they've been handled, so whatever a crashed worker was in the middle of
+// gets picked up again - either by itself after a restart, or by another worker once it's been idle long enough
+public class RedisStreamListenerService
+{
+    private const int BatchSize = 100;
+    private static readonly TimeSpan PollInterval = TimeSpan.FromMilliseconds(100);
+    private static readonly TimeSpan ErrorBackoff = TimeSpan.FromSeconds(5);
+    private static readonly TimeSpan ClaimInterval = TimeSpan.FromMinutes(1);
+    private static readonly TimeSpan ClaimIdleTime = TimeSpan.FromMinutes(1);
+
+    private readonly ILogger _logger;
+    private readonly RedisService _redis;
+    private readonly HttpListenerService _events;
+    private readonly string _consumer;
+
+    public RedisStreamListenerService(ILogger logger, RedisService redis, HttpListenerService events, BotConfig config)
+    {
+        _logger = logger.ForContext<RedisStreamListenerService>();
+        _redis = redis;
+        _events = events;
+        // stable across restarts, so we get our own pending entries back
+        _consumer = config.Cluster?.NodeName ?? Environment.MachineName;
+    }
+
+    public void Start(string key, string group, CancellationToken ct)
+    {
+        _ = Task.Run(async () =>
+        {
+            try
+            {
+                await Run(key, group, ct);
+            }
+            catch (OperationCanceledException) { }
+            catch (Exception e)
+            {
+                _logger.Error(e, "Redis stream listener for {Key} stopped", key);
+            }
+        }, ct);
+    }
+
+    private async Task Run(string key, string group, CancellationToken ct)
+    {
+        var db = _redis.Connection!.GetDatabase();
+
+        try
+        {
+            await db.StreamCreateConsumerGroupAsync(key, group, StreamPosition.NewMessages, createStream: true);
+        }
+        catch (RedisServerException e) when (e.Message.StartsWith("BUSYGROUP"))
+        {
+            // someone else already made it
+        }
+
+        _logger.Information("Reading events from redis stream {Key} as {Consumer} in group {Group}", key, _consumer, group);
+
+        // "0" reads entries delivered to us but never acked, ">" reads new ones
+        var position = "0";
+        var nextClaim = DateTimeOffset.UtcNow + ClaimInterval;
+        while (!ct.IsCancellationRequested)
+        {
+            try
+            {
+                // take over entries from workers that went away without acking them
+                if (DateTimeOffset.UtcNow >= nextClaim)
+                {
+                    nextClaim = DateTimeOffset.UtcNow + ClaimInterval;
+                    var claimed = await db.StreamAutoClaimIdsOnlyAsync(key, group, _consumer,
+                        (long)ClaimIdleTime.TotalMilliseconds, "0-0", BatchSize);
+                    if (claimed.ClaimedIds.Length > 0)
+                    {
+                        _logger.Information("Claimed {Count} unacked events from other consumers", claimed.ClaimedIds.Length);
+                        position = "0";
+                    }
+                }
+
+                var entries = await db.StreamReadGroupAsync(key, group, _consumer, position, BatchSize);
+                if (entries.Length == 0)
+                {
+                    if (position == "0")
+                        position = ">";
+                    else
+                        await Task.Delay(PollInterval, ct);
+                    continue;
+                }
+
+                await Task.WhenAll(entries.Select(entry => Handle(db, key, group, entry)));
+            }
+            catch (RedisException e)
+            {
+                _logger.Error(e, "Failed to read events from redis stream {Key}", key);
+                await Task.Delay(ErrorBackoff, ct);
+            }
+        }
+    }
+
+    private async Task Handle(IDatabase db, string key, string group, StreamEntry entry)
+    {
+        try
+        {
+            var rawEvent = (string?)entry["event"];
+            if (int.TryParse((string?)entry["shard_id"], out var shardId) && rawEvent != null)
+                await _events.HandleGatewayEvent(shardId, rawEvent);
+            else
+                _logger.Warning("Skipping malformed redis stream entry {EntryId}", entry.Id);
+        }
+        catch (Exception e)
+        {
+            // acked anyway, an event that fails to handle would otherwise be retried forever
+            _logger.Error(e, "Failed to handle event {EntryId} from redis stream", entry.Id);
+        }
+
+        await db.StreamAcknowledgeAsync(key, group, entry.Id);
+    }
+}
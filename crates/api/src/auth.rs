@@ -7,14 +7,22 @@ pub const INTERNAL_APPID_HEADER: &'static str = "x-pluralkit-appid";
 pub struct AuthState {
     system_id: Option<i32>,
     app_id: Option<i32>,
+    // identifies the token used, without being the token
+    token_id: Option<String>,
     internal: bool,
 }
 
 impl AuthState {
-    pub fn new(system_id: Option<i32>, app_id: Option<i32>, internal: bool) -> Self {
+    pub fn new(
+        system_id: Option<i32>,
+        app_id: Option<i32>,
+        token_id: Option<String>,
+        internal: bool,
+    ) -> Self {
         Self {
             system_id,
             app_id,
+            token_id,
             internal,
         }
     }
@@ -27,6 +35,10 @@ impl AuthState {
         self.app_id
     }
 
+    pub fn token_id(&self) -> Option<&str> {
+        self.token_id.as_deref()
+    }
+
     pub fn internal(&self) -> bool {
         self.internal
     }
@@ -0,0 +1,287 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use pk_macros::api_endpoint;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sqlx::types::{Uuid, chrono::NaiveDateTime};
+use tracing::info;
+
+use pluralkit_models::{PKSystem, PrivacyLevel};
+
+use crate::{
+    ApiContext,
+    auth::AuthState,
+    error::{self, PKError, ValidationError},
+    middleware::params::RequestAbout,
+};
+
+// everything in here is staff-only, and requires the internal auth token
+fn require_internal(auth: &AuthState) -> Result<(), PKError> {
+    if auth.internal() {
+        Ok(())
+    } else {
+        Err(error::GENERIC_MISSING_PERMISSIONS)
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct AccountRow {
+    uid: i64,
+    system: Option<i32>,
+    abuse_log: Option<i32>,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+struct AbuseLog {
+    #[serde(skip)]
+    id: i32,
+    uuid: Uuid,
+    description: Option<String>,
+    deny_bot_usage: bool,
+    created: NaiveDateTime,
+}
+
+#[api_endpoint]
+pub async fn get_account(
+    Extension(auth): Extension<AuthState>,
+    State(ctx): State<ApiContext>,
+    Path(account_id): Path<i64>,
+) -> Json<Value> {
+    require_internal(&auth)?;
+
+    let Some(account) = sqlx::query_as::<_, AccountRow>(
+        "select uid, system, abuse_log from accounts where uid = $1",
+    )
+    .bind(account_id)
+    .fetch_optional(&ctx.db)
+    .await?
+    else {
+        return Err(error::ACCOUNT_NOT_FOUND);
+    };
+
+    let system = match account.system {
+        Some(system_id) => {
+            sqlx::query_as::<_, PKSystem>("select * from systems where id = $1")
+                .bind(system_id)
+                .fetch_optional(&ctx.db)
+                .await?
+        }
+        None => None,
+    };
+
+    let abuse_log = match account.abuse_log {
+        Some(abuse_log_id) => {
+            sqlx::query_as::<_, AbuseLog>("select * from abuse_logs where id = $1")
+                .bind(abuse_log_id)
+                .fetch_optional(&ctx.db)
+                .await?
+        }
+        None => None,
+    };
+
+    Ok(Json(json!({
+        "account_id": account.uid.to_string(),
+        "system": system.map(|s| s.to_json(PrivacyLevel::Private)),
+        "abuse_log": abuse_log,
+    })))
+}
+
+#[api_endpoint]
+pub async fn get_abuse_log(
+    Extension(auth): Extension<AuthState>,
+    State(ctx): State<ApiContext>,
+    Path(abuse_log_id): Path<Uuid>,
+) -> Json<Value> {
+    require_internal(&auth)?;
+
+    let Some(abuse_log) =
+        sqlx::query_as::<_, AbuseLog>("select * from abuse_logs where uuid = $1")
+            .bind(abuse_log_id)
+            .fetch_optional(&ctx.db)
+            .await?
+    else {
+        return Err(error::ABUSE_LOG_NOT_FOUND);
+    };
+
+    let accounts: Vec<i64> = sqlx::query_scalar("select uid from accounts where abuse_log = $1")
+        .bind(abuse_log.id)
+        .fetch_all(&ctx.db)
+        .await?;
+
+    Ok(Json(json!({
+        "abuse_log": abuse_log,
+        "accounts": accounts.iter().map(|v| v.to_string()).collect::<Vec<String>>(),
+    })))
+}
+
+// same as member/system descriptions
+const MAX_DESCRIPTION_LENGTH: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct BanRequest {
+    description: Option<String>,
+}
+
+#[api_endpoint]
+pub async fn ban_account(
+    Extension(auth): Extension<AuthState>,
+    State(ctx): State<ApiContext>,
+    Path(account_id): Path<i64>,
+    Json(req): Json<BanRequest>,
+) -> Json<Value> {
+    require_internal(&auth)?;
+
+    if let Some(description) = req.description.as_ref()
+        && description.chars().count() > MAX_DESCRIPTION_LENGTH
+    {
+        return Err(
+            error::MODEL_PARSE_ERROR.with_errors(vec![ValidationError::too_long(
+                "description",
+                description.chars().count(),
+                MAX_DESCRIPTION_LENGTH,
+            )]),
+        );
+    }
+
+    let mut tx = ctx.db.begin().await?;
+
+    let existing: Option<Option<i32>> =
+        sqlx::query_scalar("select abuse_log from accounts where uid = $1")
+            .bind(account_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+    // if the account is already linked to an abuse log, reuse it
+    // otherwise, create a new one (this also creates the account row if it doesn't exist)
+    let abuse_log: AbuseLog = if let Some(Some(abuse_log_id)) = existing {
+        sqlx::query_as(
+            "update abuse_logs set deny_bot_usage = true, description = coalesce($2, description) where id = $1 returning *",
+        )
+        .bind(abuse_log_id)
+        .bind(req.description)
+        .fetch_one(&mut *tx)
+        .await?
+    } else {
+        let abuse_log: AbuseLog = sqlx::query_as(
+            "insert into abuse_logs (description, deny_bot_usage) values ($1, true) returning *",
+        )
+        .bind(req.description)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            "insert into accounts (uid, abuse_log) values ($1, $2) on conflict (uid) do update set abuse_log = $2",
+        )
+        .bind(account_id)
+        .bind(abuse_log.id)
+        .execute(&mut *tx)
+        .await?;
+
+        abuse_log
+    };
+
+    tx.commit().await?;
+
+    info!(
+        account_id,
+        abuse_log = %abuse_log.uuid,
+        "denied bot usage for account via admin api"
+    );
+
+    Ok(Json(json!({ "abuse_log": abuse_log })))
+}
+
+#[api_endpoint]
+pub async fn unban_account(
+    Extension(auth): Extension<AuthState>,
+    State(ctx): State<ApiContext>,
+    Path(account_id): Path<i64>,
+) -> Json<Value> {
+    require_internal(&auth)?;
+
+    let Some(abuse_log) = sqlx::query_as::<_, AbuseLog>(
+        "update abuse_logs set deny_bot_usage = false where id = (select abuse_log from accounts where uid = $1) returning *",
+    )
+    .bind(account_id)
+    .fetch_optional(&ctx.db)
+    .await?
+    else {
+        return Err(error::ABUSE_LOG_NOT_FOUND);
+    };
+
+    info!(
+        account_id,
+        abuse_log = %abuse_log.uuid,
+        "allowed bot usage for account via admin api"
+    );
+
+    Ok(Json(json!({ "abuse_log": abuse_log })))
+}
+
+#[api_endpoint]
+pub async fn reset_system_token(
+    Extension(auth): Extension<AuthState>,
+    Extension(about): Extension<RequestAbout>,
+    State(ctx): State<ApiContext>,
+) -> StatusCode {
+    require_internal(&auth)?;
+
+    let RequestAbout::System(system_id) = about else {
+        unreachable!()
+    };
+
+    // the user can generate a new token with `pk;token refresh`
+    sqlx::query("update systems set token = null where id = $1")
+        .bind(system_id)
+        .execute(&ctx.db)
+        .await?;
+
+    info!(system = system_id, "reset system token via admin api");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[api_endpoint]
+pub async fn get_system_images(
+    Extension(auth): Extension<AuthState>,
+    Extension(about): Extension<RequestAbout>,
+    State(ctx): State<ApiContext>,
+) -> Json<Value> {
+    require_internal(&auth)?;
+
+    let RequestAbout::System(system_id) = about else {
+        unreachable!()
+    };
+
+    let system_uuid: Uuid = sqlx::query_scalar("select uuid from systems where id = $1")
+        .bind(system_id)
+        .fetch_one(&ctx.db)
+        .await?;
+
+    let images = libpk::db::repository::avatars::get_by_system(&ctx.db, system_uuid).await?;
+
+    Ok(Json(Value::Array(
+        images
+            .into_iter()
+            .map(|image| {
+                json!({
+                    "id": image.id,
+                    "kind": image.kind,
+                    "url": image.url,
+                    "content_type": image.content_type,
+                    "file_size": image.file_size,
+                    "width": image.width,
+                    "height": image.height,
+                    "uploaded_at": image.uploaded_at,
+                    "original_url": image.original_url,
+                    "original_attachment_id": image.original_attachment_id.map(|v| v.to_string()),
+                    "uploaded_by_account": image.uploaded_by_account.map(|v| v.to_string()),
+                })
+            })
+            .collect(),
+    )))
+}
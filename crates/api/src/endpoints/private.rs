@@ -1,11 +1,23 @@
-use crate::ApiContext;
-use axum::{extract::State, response::Json};
+use crate::{
+    ApiContext,
+    error::{self, ValidationError},
+};
+use axum::{
+    extract::{Query, State},
+    response::Json,
+};
 use fred::interfaces::*;
-use libpk::state::ShardState;
+use libpk::state::{
+    SHARD_HISTORY_RETENTION, ShardLatencySample, ShardState, ShardTransition, shard_latency_key,
+    shard_transitions_key, shard_uptime,
+};
 use pk_macros::api_endpoint;
 use serde::Deserialize;
 use serde_json::{Value, json};
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 #[allow(dead_code)]
 #[derive(Deserialize)]
@@ -15,8 +27,92 @@ struct ClusterStats {
     pub channel_count: i32,
 }
 
+#[derive(Deserialize)]
+pub struct ShardStateQuery {
+    /// how far back to report history for, like `24h`, `7d` or a number of seconds
+    history: Option<String>,
+}
+
+fn parse_period(period: &str) -> Option<i64> {
+    let (num, unit) = match period.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => period.split_at(idx),
+        None => (period, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    num.parse::<i64>()
+        .ok()
+        .and_then(|num| num.checked_mul(multiplier))
+        .filter(|secs| *secs > 0)
+}
+
+fn parse_entries<T: serde::de::DeserializeOwned>(entries: Vec<String>) -> Vec<T> {
+    entries
+        .iter()
+        .filter_map(|v| serde_json::from_str(v).ok())
+        .collect()
+}
+
+async fn shard_history(
+    ctx: &ApiContext,
+    shard_id: i32,
+    start: i64,
+    end: i64,
+) -> anyhow::Result<Value> {
+    let key = shard_transitions_key(shard_id);
+
+    let (before, during, latency) = tokio::try_join!(
+        // the last transition before the window tells us what state the shard started in
+        ctx.redis.zrevrangebyscore::<Vec<String>, _, _, _>(
+            &key,
+            start as f64,
+            "-inf",
+            false,
+            Some((0, 1))
+        ),
+        ctx.redis.zrangebyscore::<Vec<String>, _, _, _>(
+            &key,
+            format!("({start}"),
+            "+inf",
+            false,
+            None
+        ),
+        ctx.redis.zrangebyscore::<Vec<String>, _, _, _>(
+            shard_latency_key(shard_id),
+            start as f64,
+            "+inf",
+            false,
+            None
+        ),
+    )?;
+
+    let mut transitions = parse_entries::<ShardTransition>(before);
+    transitions.extend(parse_entries::<ShardTransition>(during));
+    let latency = parse_entries::<ShardLatencySample>(latency);
+
+    let uptime = shard_uptime(&transitions, start, end);
+
+    Ok(json!({
+        "shard_id": shard_id,
+        "uptime": uptime,
+        "transitions": transitions
+            .into_iter()
+            .filter(|t| t.timestamp > start)
+            .collect::<Vec<_>>(),
+        "latency": latency,
+    }))
+}
+
 #[api_endpoint]
-pub async fn discord_state(State(ctx): State<ApiContext>) -> Json<Value> {
+pub async fn discord_state(
+    State(ctx): State<ApiContext>,
+    Query(query): Query<ShardStateQuery>,
+) -> Json<Value> {
     let mut shard_status = ctx
         .redis
         .hgetall::<HashMap<String, String>, &str>("pluralkit:shardstatus")
@@ -27,8 +123,43 @@ pub async fn discord_state(State(ctx): State<ApiContext>) -> Json<Value> {
 
     shard_status.sort_by(|a, b| b.shard_id.cmp(&a.shard_id));
 
+    let Some(history) = query.history else {
+        return Ok(Json(json!({
+            "shards": shard_status,
+        })));
+    };
+
+    let Some(period) = parse_period(&history) else {
+        return Err(error::MODEL_PARSE_ERROR.with_errors(vec![ValidationError::invalid("history")]));
+    };
+    // we don't have anything older than this
+    let period = period.min(SHARD_HISTORY_RETENTION);
+
+    let end = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let start = end - period;
+
+    let shard_history_list = futures::future::try_join_all(
+        shard_status
+            .iter()
+            .map(|shard| shard_history(&ctx, shard.shard_id, start, end)),
+    )
+    .await?;
+
+    // average over the shards we know anything about
+    let uptimes: Vec<f64> = shard_history_list
+        .iter()
+        .filter_map(|h| h["uptime"].as_f64())
+        .collect();
+    let cluster_uptime =
+        (!uptimes.is_empty()).then(|| uptimes.iter().sum::<f64>() / uptimes.len() as f64);
+
     Ok(Json(json!({
         "shards": shard_status,
+        "history": {
+            "period": period,
+            "uptime": cluster_uptime,
+            "shards": shard_history_list,
+        },
     })))
 }
 
@@ -187,3 +318,29 @@ pub async fn discord_callback(
     )
         .into_response()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_period;
+
+    #[test]
+    fn parses_periods() {
+        assert_eq!(parse_period("90"), Some(90));
+        assert_eq!(parse_period("30s"), Some(30));
+        assert_eq!(parse_period("5m"), Some(5 * 60));
+        assert_eq!(parse_period("24h"), Some(24 * 60 * 60));
+        assert_eq!(parse_period("7d"), Some(7 * 24 * 60 * 60));
+    }
+
+    #[test]
+    fn rejects_invalid_periods() {
+        assert_eq!(parse_period(""), None);
+        assert_eq!(parse_period("0h"), None);
+        assert_eq!(parse_period("h"), None);
+        assert_eq!(parse_period("7w"), None);
+        assert_eq!(parse_period("-5m"), None);
+        assert_eq!(parse_period("1d2h"), None);
+        // would overflow
+        assert_eq!(parse_period(&format!("{}d", i64::MAX / 2)), None);
+    }
+}
@@ -1,11 +1,24 @@
-use axum::{Extension, Json, extract::State, response::IntoResponse};
+use axum::{
+    Extension, Json,
+    extract::{Query, State},
+    response::IntoResponse,
+};
 use pk_macros::api_endpoint;
+use serde::Deserialize;
 use serde_json::{Value, json};
-use sqlx::Postgres;
+use sqlx::{
+    Postgres,
+    types::{Uuid, chrono::NaiveDateTime},
+};
 
 use pluralkit_models::{PKSystemConfig, PrivacyLevel};
 
-use crate::{ApiContext, auth::AuthState, error::fail, middleware::params::RequestAbout};
+use crate::{
+    ApiContext,
+    auth::AuthState,
+    error::{self, ValidationError, fail},
+    middleware::params::RequestAbout,
+};
 
 #[api_endpoint]
 pub async fn get_system_settings(
@@ -55,3 +68,87 @@ pub async fn get_system_settings(
         }),
     }))
 }
+
+#[derive(sqlx::FromRow)]
+struct AuditLogEntry {
+    id: i64,
+    app_id: Option<i32>,
+    token_id: Option<String>,
+    method: String,
+    route: String,
+    entity_type: Option<String>,
+    entity_uuid: Option<Uuid>,
+    request: Option<Value>,
+    diff: Option<Value>,
+    created: NaiveDateTime,
+}
+
+#[derive(Deserialize)]
+pub struct AuditLogQuery {
+    before: Option<i64>,
+    limit: Option<i64>,
+}
+
+const AUDIT_LOG_PAGE_SIZE: i64 = 100;
+
+#[api_endpoint]
+pub async fn get_system_audit_log(
+    Extension(auth): Extension<AuthState>,
+    Extension(about): Extension<RequestAbout>,
+    State(ctx): State<ApiContext>,
+    Query(query): Query<AuditLogQuery>,
+) -> Json<Value> {
+    let RequestAbout::System(system_id) = about else {
+        unreachable!()
+    };
+
+    // audit log is only visible to the system itself
+    if !matches!(auth.access_level_for(&about), PrivacyLevel::Private) {
+        return Err(error::GENERIC_MISSING_PERMISSIONS);
+    }
+
+    let limit = query.limit.unwrap_or(AUDIT_LOG_PAGE_SIZE);
+    if !(1..=AUDIT_LOG_PAGE_SIZE).contains(&limit) {
+        return Err(
+            error::MODEL_PARSE_ERROR.with_errors(vec![ValidationError::new(
+                "limit",
+                format!("Limit must be between 1 and {AUDIT_LOG_PAGE_SIZE}."),
+            )]),
+        );
+    }
+
+    let entries: Vec<AuditLogEntry> = sqlx::query_as(
+        "select * from audit_log where system = $1 and ($2::bigint is null or id < $2) order by id desc limit $3",
+    )
+    .bind(system_id)
+    .bind(query.before)
+    .bind(limit)
+    .fetch_all(&ctx.db)
+    .await?;
+
+    // pass this as `before` to get the next page
+    let next = (entries.len() as i64 == limit)
+        .then(|| entries.last().map(|entry| entry.id.to_string()))
+        .flatten();
+
+    Ok(Json(json!({
+        "entries": entries
+            .into_iter()
+            .map(|entry| {
+                json!({
+                    "id": entry.id.to_string(),
+                    "app_id": entry.app_id,
+                    "token_id": entry.token_id,
+                    "method": entry.method,
+                    "route": entry.route,
+                    "entity_type": entry.entity_type,
+                    "entity_id": entry.entity_uuid,
+                    "request": entry.request,
+                    "diff": entry.diff,
+                    "timestamp": entry.created.and_utc(),
+                })
+            })
+            .collect::<Vec<Value>>(),
+        "next": next,
+    })))
+}
@@ -2,18 +2,52 @@ use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
 };
-use std::fmt;
+use serde_json::{Map, Value, json};
+use std::{borrow::Cow, fmt};
 
-// todo: model parse errors
 #[derive(Debug)]
 pub struct PKError {
     pub response_code: StatusCode,
     pub json_code: i32,
-    pub message: &'static str,
+    pub message: Cow<'static, str>,
+
+    /// field-level validation errors, serialized into the `errors` object
+    pub errors: Vec<ValidationError>,
 
     pub inner: Option<anyhow::Error>,
 }
 
+impl PKError {
+    pub fn with_errors(mut self, errors: Vec<ValidationError>) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    // this matches the legacy (dotnet) API's error format
+    pub fn to_json(&self) -> Value {
+        let mut res = json!({
+            "message": self.message,
+            "code": self.json_code,
+        });
+
+        if !self.errors.is_empty() {
+            let mut errors = Map::new();
+            for err in self.errors.iter() {
+                let Value::Array(list) = errors
+                    .entry(err.key.clone())
+                    .or_insert_with(|| Value::Array(Vec::new()))
+                else {
+                    unreachable!()
+                };
+                list.push(err.to_json());
+            }
+            res["errors"] = Value::Object(errors);
+        }
+
+        res
+    }
+}
+
 impl fmt::Display for PKError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -28,7 +62,8 @@ impl Clone for PKError {
         PKError {
             response_code: self.response_code,
             json_code: self.json_code,
-            message: self.message,
+            message: self.message.clone(),
+            errors: self.errors.clone(),
             inner: None,
         }
     }
@@ -47,20 +82,78 @@ where
 
 impl IntoResponse for PKError {
     fn into_response(self) -> Response {
-        if let Some(inner) = self.inner {
+        if let Some(inner) = self.inner.as_ref() {
             tracing::error!(?inner, "error returned from handler");
         }
         crate::util::json_err(
             self.response_code,
-            serde_json::to_string(&serde_json::json!({
-                "message": self.message,
-                "code": self.json_code,
-            }))
-            .unwrap(),
+            serde_json::to_string(&self.to_json()).unwrap(),
         )
     }
 }
 
+#[derive(Debug, Clone)]
+enum ValidationReason {
+    TooLong {
+        actual_length: usize,
+        max_length: usize,
+    },
+    Invalid,
+    Other(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ValidationError {
+    /// path to the field, e.g. `proxy_tags[0].prefix`
+    key: String,
+    reason: ValidationReason,
+}
+
+impl ValidationError {
+    pub fn new(key: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            reason: ValidationReason::Other(reason.into()),
+        }
+    }
+
+    pub fn invalid(key: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            reason: ValidationReason::Invalid,
+        }
+    }
+
+    pub fn too_long(key: impl Into<String>, actual_length: usize, max_length: usize) -> Self {
+        Self {
+            key: key.into(),
+            reason: ValidationReason::TooLong {
+                actual_length,
+                max_length,
+            },
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        match &self.reason {
+            ValidationReason::TooLong {
+                actual_length,
+                max_length,
+            } => json!({
+                "message": format!("Field {} is too long.", self.key),
+                "actual_length": actual_length,
+                "max_length": max_length,
+            }),
+            ValidationReason::Invalid => json!({
+                "message": format!("Field {} is invalid.", self.key),
+            }),
+            ValidationReason::Other(message) => json!({
+                "message": message,
+            }),
+        }
+    }
+}
+
 macro_rules! fail {
     ($($stuff:tt)+) => {{
         tracing::error!($($stuff)+);
@@ -71,20 +164,79 @@ macro_rules! fail {
 pub(crate) use fail;
 
 macro_rules! define_error {
-    ( $name:ident, $response_code:expr, $json_code:expr, $message:expr ) => {
-        #[allow(dead_code)]
+    ( $(#[$attr:meta])* $name:ident, $response_code:expr, $json_code:expr, $message:expr ) => {
+        $(#[$attr])*
         pub const $name: PKError = PKError {
             response_code: $response_code,
             json_code: $json_code,
-            message: $message,
+            message: Cow::Borrowed($message),
+            errors: Vec::new(),
             inner: None,
         };
     };
 }
 
+// errors that include a reference to the entity the user passed in
+macro_rules! define_ref_error {
+    ( $(#[$attr:meta])* $name:ident, $response_code:expr, $json_code:expr, $message:expr ) => {
+        $(#[$attr])*
+        pub fn $name(entity_ref: &str) -> PKError {
+            PKError {
+                response_code: $response_code,
+                json_code: $json_code,
+                message: Cow::Owned(format!($message, entity_ref)),
+                errors: Vec::new(),
+                inner: None,
+            }
+        }
+    };
+}
+
+// the rest of the legacy API's catalogue is here too, so the codes stay the same as endpoints
+// are ported; the ones nothing returns yet are marked as such
 define_error! { GENERIC_BAD_REQUEST, StatusCode::BAD_REQUEST, 0, "400: Bad Request" }
+define_error! { GENERIC_AUTH_ERROR, StatusCode::UNAUTHORIZED, 0, "401: Missing or invalid Authorization header" }
+define_error! { GENERIC_MISSING_PERMISSIONS, StatusCode::FORBIDDEN, 0, "403: Missing permissions to access this resource" }
 define_error! { GENERIC_SERVER_ERROR, StatusCode::INTERNAL_SERVER_ERROR, 0, "500: Internal Server Error" }
+
+// 2xxxx: not found
 define_error! { SYSTEM_NOT_FOUND, StatusCode::NOT_FOUND, 20001, "System not found." }
 define_error! { MEMBER_NOT_FOUND, StatusCode::NOT_FOUND, 20002, "Member not found." }
+define_ref_error! { #[allow(dead_code)] member_not_found_with_ref, StatusCode::NOT_FOUND, 20003, "Member '{}' not found." }
 define_error! { GROUP_NOT_FOUND, StatusCode::NOT_FOUND, 20004, "Group not found." }
+define_ref_error! { #[allow(dead_code)] group_not_found_with_ref, StatusCode::NOT_FOUND, 20005, "Group '{}' not found." }
+define_error! { #[allow(dead_code)] MESSAGE_NOT_FOUND, StatusCode::NOT_FOUND, 20006, "Message not found." }
 define_error! { SWITCH_NOT_FOUND, StatusCode::NOT_FOUND, 20007, "Switch not found." }
+define_error! { #[allow(dead_code)] SWITCH_NOT_FOUND_PUBLIC, StatusCode::NOT_FOUND, 20008, "Switch not found, switch associated with different system, or unauthorized to view front history." }
+define_error! { #[allow(dead_code)] SYSTEM_GUILD_NOT_FOUND, StatusCode::NOT_FOUND, 20009, "No system guild settings found for target guild." }
+define_error! { #[allow(dead_code)] MEMBER_GUILD_NOT_FOUND, StatusCode::NOT_FOUND, 20010, "No member guild settings found for target guild." }
+
+// 3xxxx: authorization
+define_error! { #[allow(dead_code)] UNAUTHORIZED_MEMBER_LIST, StatusCode::FORBIDDEN, 30001, "Unauthorized to view member list" }
+define_error! { #[allow(dead_code)] UNAUTHORIZED_GROUP_LIST, StatusCode::FORBIDDEN, 30002, "Unauthorized to view group list" }
+define_error! { #[allow(dead_code)] UNAUTHORIZED_GROUP_MEMBER_LIST, StatusCode::FORBIDDEN, 30003, "Unauthorized to view group member list" }
+define_error! { #[allow(dead_code)] UNAUTHORIZED_CURRENT_FRONTERS, StatusCode::FORBIDDEN, 30004, "Unauthorized to view current fronters." }
+define_error! { #[allow(dead_code)] UNAUTHORIZED_FRONT_HISTORY, StatusCode::FORBIDDEN, 30005, "Unauthorized to view front history." }
+define_error! { #[allow(dead_code)] NOT_OWN_MEMBER, StatusCode::FORBIDDEN, 30006, "Target member is not part of your system." }
+define_error! { #[allow(dead_code)] NOT_OWN_GROUP, StatusCode::FORBIDDEN, 30007, "Target group is not part of your system." }
+define_ref_error! { #[allow(dead_code)] not_own_member_with_ref, StatusCode::FORBIDDEN, 30008, "Member '{}' is not part of your system." }
+define_ref_error! { #[allow(dead_code)] not_own_group_with_ref, StatusCode::FORBIDDEN, 30009, "Group '{}' is not part of your system." }
+
+// 4xxxx: validation and limits
+// use `.with_errors()` to add field-level details
+define_error! { MODEL_PARSE_ERROR, StatusCode::BAD_REQUEST, 40001, "Error parsing JSON model" }
+define_error! { #[allow(dead_code)] MISSING_AUTOPROXY_MEMBER, StatusCode::BAD_REQUEST, 40002, "Missing autoproxy member for member-mode autoproxy." }
+define_error! { #[allow(dead_code)] DUPLICATE_MEMBERS_IN_LIST, StatusCode::BAD_REQUEST, 40003, "Duplicate members in member list." }
+define_error! { #[allow(dead_code)] SAME_SWITCH_MEMBERS, StatusCode::BAD_REQUEST, 40004, "Member list identical to current fronter list." }
+define_error! { #[allow(dead_code)] SAME_SWITCH_TIMESTAMP, StatusCode::BAD_REQUEST, 40005, "Switch with provided timestamp already exists." }
+define_error! { #[allow(dead_code)] INVALID_SWITCH_ID, StatusCode::BAD_REQUEST, 40006, "Invalid switch ID." }
+define_error! { #[allow(dead_code)] MEMBER_LIMIT_REACHED, StatusCode::BAD_REQUEST, 40007, "Member limit reached." }
+define_error! { #[allow(dead_code)] GROUP_LIMIT_REACHED, StatusCode::BAD_REQUEST, 40008, "Group limit reached." }
+define_error! { #[allow(dead_code)] PATCH_LATCH_MEMBER, StatusCode::BAD_REQUEST, 40009, "Cannot patch autoproxy member with latch-mode autoproxy." }
+
+// 5xxxx
+define_error! { #[allow(dead_code)] UNIMPLEMENTED, StatusCode::NOT_IMPLEMENTED, 50001, "Unimplemented" }
+
+// internal (admin) errors
+define_error! { ACCOUNT_NOT_FOUND, StatusCode::NOT_FOUND, 0, "Account not found." }
+define_error! { ABUSE_LOG_NOT_FOUND, StatusCode::NOT_FOUND, 0, "Abuse log not found." }
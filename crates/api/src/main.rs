@@ -13,6 +13,7 @@ use hyper_util::{
     rt::TokioExecutor,
 };
 use libpk::config;
+use std::sync::Arc;
 use tracing::{info, warn};
 
 use pk_macros::api_endpoint;
@@ -28,6 +29,10 @@ pub struct ApiContext {
     pub db: sqlx::postgres::PgPool,
     pub redis: fred::clients::RedisPool,
 
+    pub cors: Arc<middleware::cors::CorsPolicy>,
+
+    pub entity_cache: Arc<middleware::params::EntityCache>,
+
     rproxy_uri: String,
     rproxy_client: Client<HttpConnector, Body>,
 }
@@ -74,6 +79,7 @@ fn router(ctx: ApiContext) -> Router {
         .route("/v2/systems/{system_id}", patch(rproxy))
         .route("/v2/systems/{system_id}/settings", get(endpoints::system::get_system_settings))
         .route("/v2/systems/{system_id}/settings", patch(rproxy))
+        .route("/v2/systems/{system_id}/audit", get(endpoints::system::get_system_audit_log))
 
         .route("/v2/systems/{system_id}/members", get(rproxy))
         .route("/v2/members", post(rproxy))
@@ -124,10 +130,18 @@ fn router(ctx: ApiContext) -> Router {
         .route("/private/discord/shard_state", get(endpoints::private::discord_state))
         .route("/private/stats", get(endpoints::private::meta))
 
+        .route("/private/admin/accounts/{account_id}", get(endpoints::admin::get_account))
+        .route("/private/admin/accounts/{account_id}/ban", post(endpoints::admin::ban_account))
+        .route("/private/admin/accounts/{account_id}/unban", post(endpoints::admin::unban_account))
+        .route("/private/admin/abuse_logs/{abuse_log_id}", get(endpoints::admin::get_abuse_log))
+        .route("/private/admin/systems/{system_id}/reset_token", post(endpoints::admin::reset_system_token))
+        .route("/private/admin/systems/{system_id}/images", get(endpoints::admin::get_system_images))
+
         .route("/v2/systems/{system_id}/oembed.json", get(rproxy))
         .route("/v2/members/{member_id}/oembed.json", get(rproxy))
         .route("/v2/groups/{group_id}/oembed.json", get(rproxy))
 
+        .layer(axum::middleware::from_fn_with_state(ctx.clone(), middleware::audit::audit))
         .layer(axum::middleware::from_fn_with_state(
             if config.api().use_ratelimiter {
                 Some(ctx.redis.clone())
@@ -141,7 +155,7 @@ fn router(ctx: ApiContext) -> Router {
         .layer(axum::middleware::from_fn_with_state(ctx.clone(), middleware::params::params))
         .layer(axum::middleware::from_fn_with_state(ctx.clone(), middleware::auth::auth))
         .layer(axum::middleware::from_fn(middleware::logger::logger))
-        .layer(axum::middleware::from_fn(middleware::cors::cors))
+        .layer(axum::middleware::from_fn_with_state(ctx.clone(), middleware::cors::cors))
         .layer(tower_http::catch_panic::CatchPanicLayer::custom(util::handle_panic))
 
         .with_state(ctx)
@@ -162,10 +176,16 @@ async fn main() -> anyhow::Result<()> {
         db,
         redis,
 
+        cors: Arc::new(middleware::cors::cors_policy()?),
+
+        entity_cache: Arc::new(middleware::params::entity_cache()),
+
         rproxy_uri: rproxy_uri[..rproxy_uri.len() - 1].to_string(),
         rproxy_client,
     };
 
+    tokio::spawn(middleware::cors::app_origins_loop(ctx.clone()));
+
     let app = router(ctx);
 
     let addr: &str = libpk::config.api().addr.as_ref();
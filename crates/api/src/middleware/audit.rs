@@ -0,0 +1,461 @@
+use axum::{
+    body::{Body, HttpBody, to_bytes},
+    extract::{MatchedPath, Request, State},
+    http::{HeaderMap, Method, header::CONTENT_LENGTH},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::url_params::UrlParams,
+};
+use pluralkit_models::{PKSystem, PKSystemConfig, PrivacyLevel};
+use serde_json::{Map, Value, json};
+use sqlx::types::Uuid;
+use std::collections::HashMap;
+use tracing::error;
+
+use crate::{
+    ApiContext, auth::AuthState, error::GENERIC_SERVER_ERROR, middleware::params::RequestAbout,
+};
+
+// request/response bodies larger than this are passed through, but not recorded
+const MAX_BODY_SIZE: usize = 10_000_000;
+
+// these should never end up in the audit log
+const REDACTED_FIELDS: [&str; 3] = ["token", "webhook_url", "webhook_token"];
+
+fn redact(mut value: Value) -> Value {
+    match value {
+        Value::Object(ref mut obj) => {
+            for field in REDACTED_FIELDS {
+                obj.remove(field);
+            }
+            for (_, v) in obj.iter_mut() {
+                *v = redact(v.take());
+            }
+        }
+        Value::Array(ref mut list) => {
+            for v in list.iter_mut() {
+                *v = redact(v.take());
+            }
+        }
+        _ => {}
+    }
+    value
+}
+
+// members, groups and switches don't have models on this side yet,
+// so build the same fields the v2 api returns for them
+const MEMBER_JSON: &str = r#"jsonb_build_object(
+    'id', trim(hid), 'uuid', uuid, 'name', name, 'display_name', display_name, 'color', color,
+    'birthday', birthday, 'pronouns', pronouns, 'avatar_url', avatar_url,
+    'webhook_avatar_url', webhook_avatar_url, 'banner', banner_image, 'description', description,
+    'proxy_tags', to_jsonb(proxy_tags), 'keep_proxy', keep_proxy, 'tts', tts,
+    'autoproxy_enabled', allow_autoproxy, 'created', created,
+    'privacy', jsonb_build_object(
+        'visibility', case member_visibility when 1 then 'public' else 'private' end, 'name_privacy', case name_privacy when 1 then 'public' else 'private' end,
+        'description_privacy', case description_privacy when 1 then 'public' else 'private' end,
+        'birthday_privacy', case birthday_privacy when 1 then 'public' else 'private' end,
+        'pronoun_privacy', case pronoun_privacy when 1 then 'public' else 'private' end,
+        'avatar_privacy', case avatar_privacy when 1 then 'public' else 'private' end,
+        'banner_privacy', case banner_privacy when 1 then 'public' else 'private' end,
+        'metadata_privacy', case metadata_privacy when 1 then 'public' else 'private' end,
+        'proxy_privacy', case proxy_privacy when 1 then 'public' else 'private' end
+    )
+)"#;
+
+const GROUP_JSON: &str = r#"jsonb_build_object(
+    'id', trim(hid), 'uuid', uuid, 'name', name, 'display_name', display_name,
+    'description', description, 'icon', icon, 'banner', banner_image, 'color', color,
+    'created', created,
+    'privacy', jsonb_build_object(
+        'name_privacy', case name_privacy when 1 then 'public' else 'private' end,
+        'description_privacy', case description_privacy when 1 then 'public' else 'private' end,
+        'icon_privacy', case icon_privacy when 1 then 'public' else 'private' end,
+        'list_privacy', case list_privacy when 1 then 'public' else 'private' end,
+        'metadata_privacy', case metadata_privacy when 1 then 'public' else 'private' end,
+        'visibility', case visibility when 1 then 'public' else 'private' end,
+        'banner_privacy', case banner_privacy when 1 then 'public' else 'private' end
+    )
+)"#;
+
+const SWITCH_JSON: &str = r#"jsonb_build_object(
+    'id', uuid, 'timestamp', timestamp,
+    'members', (select coalesce(jsonb_agg(trim(members.hid) order by switch_members.id), '[]'::jsonb)
+        from switch_members join members on members.id = switch_members.member
+        where switch_members.switch = switches.id)
+)"#;
+
+const SYSTEM_GUILD_JSON: &str = r#"jsonb_build_object(
+    'guild_id', guild::text, 'proxying_enabled', proxy_enabled, 'tag', tag,
+    'tag_enabled', tag_enabled, 'avatar_url', avatar_url, 'display_name', display_name,
+    'name_format', name_format
+)"#;
+
+const MEMBER_GUILD_JSON: &str = r#"jsonb_build_object(
+    'guild_id', guild::text, 'display_name', display_name, 'avatar_url', avatar_url,
+    'keep_proxy', keep_proxy
+)"#;
+
+const AUTOPROXY_JSON: &str = r#"jsonb_build_object(
+    'autoproxy_mode', case autoproxy_mode when 1 then 'off' when 2 then 'front' when 3 then 'latch' else 'member' end,
+    'autoproxy_member', (select trim(hid) from members where id = autoproxy_member)
+)"#;
+
+/// what a request changes
+///
+/// routes that change something belonging to an entity (its settings, group members, ...)
+/// are about that entity, but snapshotting the entity itself would never show a change
+#[derive(Clone, Copy)]
+enum Target {
+    Entity(&'static str, i32),
+    SystemConfig(i32),
+    GroupMembers(i32),
+    MemberGroups(i32),
+    SystemGuild {
+        system: i32,
+        guild: i64,
+    },
+    MemberGuild {
+        member: i32,
+        guild: i64,
+    },
+    Autoproxy {
+        system: i32,
+        guild: i64,
+        channel: i64,
+    },
+}
+
+fn url_param(request: &Request, key: &str) -> Option<i64> {
+    match request.extensions().get::<UrlParams>()? {
+        UrlParams::Params(params) => params
+            .iter()
+            .find(|(k, _)| k.as_ref() == key)
+            .and_then(|(_, v)| v.as_str().parse().ok()),
+        _ => None,
+    }
+}
+
+impl Target {
+    /// None if the request creates something, rather than changing what it's about
+    fn new(route: &str, about: &RequestAbout, request: &Request) -> Option<Target> {
+        let (table, id) = about.entity();
+        Some(match route {
+            "/v2/systems/{system_id}/switches" => return None,
+            "/v2/systems/{system_id}/settings" => Target::SystemConfig(id),
+            "/v2/groups/{group_id}/members/add"
+            | "/v2/groups/{group_id}/members/remove"
+            | "/v2/groups/{group_id}/members/overwrite" => Target::GroupMembers(id),
+            "/v2/members/{member_id}/groups/add"
+            | "/v2/members/{member_id}/groups/remove"
+            | "/v2/members/{member_id}/groups/overwrite" => Target::MemberGroups(id),
+            "/v2/systems/{system_id}/guilds/{guild_id}" => Target::SystemGuild {
+                system: id,
+                guild: url_param(request, "guild_id")?,
+            },
+            "/v2/members/{member_id}/guilds/{guild_id}" => Target::MemberGuild {
+                member: id,
+                guild: url_param(request, "guild_id")?,
+            },
+            "/v2/systems/{system_id}/autoproxy" => {
+                let query: HashMap<String, String> =
+                    serde_urlencoded::from_str(request.uri().query().unwrap_or_default())
+                        .unwrap_or_default();
+                let param = |key: &str| query.get(key).and_then(|v| v.parse().ok()).unwrap_or(0);
+                Target::Autoproxy {
+                    system: id,
+                    guild: param("guild_id"),
+                    channel: param("channel_id"),
+                }
+            }
+            _ => Target::Entity(table, id),
+        })
+    }
+
+    fn entity_type(&self) -> &'static str {
+        match self {
+            Target::Entity(table, _) => table,
+            Target::SystemConfig(_) => "system_config",
+            Target::GroupMembers(_) => "group_members",
+            Target::MemberGroups(_) => "member_groups",
+            Target::SystemGuild { .. } => "system_guild",
+            Target::MemberGuild { .. } => "member_guild",
+            Target::Autoproxy { .. } => "autoproxy",
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct Snapshot {
+    uuid: Uuid,
+    value: Value,
+}
+
+/// what the request changes, as the api would return it to its own system,
+/// and the uuid of the entity it belongs to
+///
+/// settings that don't exist yet are `null`
+async fn snapshot(ctx: &ApiContext, target: Target) -> Option<Snapshot> {
+    let res = match target {
+        Target::Entity("systems", id) => {
+            sqlx::query_as::<_, PKSystem>("select * from systems where id = $1")
+                .bind(id)
+                .fetch_optional(&ctx.db)
+                .await
+                .map(|system| {
+                    system.map(|system| Snapshot {
+                        uuid: system.uuid,
+                        value: system.to_json(PrivacyLevel::Private),
+                    })
+                })
+        }
+        Target::SystemConfig(system) => {
+            let config = sqlx::query_as::<_, PKSystemConfig>(
+                "select * from system_config where system = $1",
+            )
+            .bind(system)
+            .fetch_optional(&ctx.db);
+            let uuid = sqlx::query_scalar::<_, Uuid>("select uuid from systems where id = $1")
+                .bind(system)
+                .fetch_optional(&ctx.db);
+            tokio::try_join!(config, uuid).map(|(config, uuid)| {
+                Some(Snapshot {
+                    uuid: uuid?,
+                    value: config.map_or(Value::Null, |config| config.to_json()),
+                })
+            })
+        }
+        _ => {
+            // the owning entity's id is always $1
+            let (sql, id, args): (String, i32, Vec<i64>) = match target {
+                Target::Entity(table, id) => {
+                    let json = match table {
+                        "members" => MEMBER_JSON,
+                        "groups" => GROUP_JSON,
+                        "switches" => SWITCH_JSON,
+                        _ => unreachable!(),
+                    };
+                    (
+                        format!("select uuid, {json} as value from {table} where id = $1"),
+                        id,
+                        vec![],
+                    )
+                }
+                Target::GroupMembers(id) => (
+                    r#"select uuid, jsonb_build_object('members', (
+                        select coalesce(jsonb_agg(trim(members.hid) order by members.hid), '[]'::jsonb)
+                        from group_members join members on members.id = group_members.member_id
+                        where group_members.group_id = groups.id
+                    )) as value from groups where id = $1"#
+                        .to_string(),
+                    id,
+                    vec![],
+                ),
+                Target::MemberGroups(id) => (
+                    r#"select uuid, jsonb_build_object('groups', (
+                        select coalesce(jsonb_agg(trim(groups.hid) order by groups.hid), '[]'::jsonb)
+                        from group_members join groups on groups.id = group_members.group_id
+                        where group_members.member_id = members.id
+                    )) as value from members where id = $1"#
+                        .to_string(),
+                    id,
+                    vec![],
+                ),
+                Target::SystemGuild { system, guild } => (
+                    format!(
+                        r#"select uuid, coalesce((
+                            select {SYSTEM_GUILD_JSON} from system_guild
+                            where system_guild.system = systems.id and guild = $2
+                        ), 'null'::jsonb) as value from systems where id = $1"#
+                    ),
+                    system,
+                    vec![guild],
+                ),
+                Target::MemberGuild { member, guild } => (
+                    format!(
+                        r#"select uuid, coalesce((
+                            select {MEMBER_GUILD_JSON} from member_guild
+                            where member_guild.member = members.id and guild = $2
+                        ), 'null'::jsonb) as value from members where id = $1"#
+                    ),
+                    member,
+                    vec![guild],
+                ),
+                Target::Autoproxy {
+                    system,
+                    guild,
+                    channel,
+                } => (
+                    format!(
+                        r#"select uuid, coalesce((
+                            select {AUTOPROXY_JSON} from autoproxy
+                            where autoproxy.system = systems.id and guild_id = $2 and channel_id = $3
+                        ), 'null'::jsonb) as value from systems where id = $1"#
+                    ),
+                    system,
+                    vec![guild, channel],
+                ),
+                Target::SystemConfig(_) => unreachable!(),
+            };
+
+            let mut query = sqlx::query_as::<_, Snapshot>(&sql).bind(id);
+            for arg in args {
+                query = query.bind(arg);
+            }
+            query.fetch_optional(&ctx.db).await
+        }
+    };
+
+    match res {
+        Ok(snapshot) => snapshot,
+        Err(error) => {
+            error!(
+                ?error,
+                entity_type = target.entity_type(),
+                "failed to snapshot entity for audit log"
+            );
+            None
+        }
+    }
+}
+
+// only buffer bodies we know the size of, anything else goes through without being recorded
+fn body_size(headers: &HeaderMap, body: &Body) -> Option<usize> {
+    let size = match headers.get(CONTENT_LENGTH) {
+        Some(value) => value.to_str().ok()?.parse().ok()?,
+        None => body.size_hint().exact()? as usize,
+    };
+    (size <= MAX_BODY_SIZE).then_some(size)
+}
+
+// only keep fields that changed
+fn diff(before: Option<Value>, after: Option<Value>) -> Value {
+    match (before, after) {
+        (Some(Value::Object(before)), Some(Value::Object(after))) => {
+            let mut changes = Map::new();
+            for (key, new) in after {
+                let old = before.get(&key).cloned().unwrap_or(Value::Null);
+                if old != new {
+                    changes.insert(key, json!({ "before": old, "after": new }));
+                }
+            }
+            Value::Object(changes)
+        }
+        (before, after) => json!({ "before": before, "after": after }),
+    }
+}
+
+pub async fn audit(State(ctx): State<ApiContext>, request: Request, next: Next) -> Response {
+    if matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) {
+        return next.run(request).await;
+    }
+
+    let auth = request
+        .extensions()
+        .get::<AuthState>()
+        .expect("should always have AuthState")
+        .clone();
+
+    // we only keep an audit log for systems
+    let Some(system_id) = auth.system_id() else {
+        return next.run(request).await;
+    };
+
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|v| v.as_str().to_string())
+        .unwrap_or("unknown".to_string());
+    let target = request
+        .extensions()
+        .get::<RequestAbout>()
+        .and_then(|about| Target::new(&route, about, &request));
+
+    let (request_json, request) = match body_size(request.headers(), request.body()) {
+        Some(_) => {
+            let (parts, body) = request.into_parts();
+            let body = match to_bytes(body, MAX_BODY_SIZE).await {
+                Ok(body) => body,
+                Err(error) => {
+                    error!(?error, "failed to read request body for audit log");
+                    return GENERIC_SERVER_ERROR.into_response();
+                }
+            };
+            (
+                serde_json::from_slice::<Value>(&body).ok().map(redact),
+                Request::from_parts(parts, Body::from(body)),
+            )
+        }
+        None => (None, request),
+    };
+
+    let before = match target {
+        Some(target) => snapshot(&ctx, target).await,
+        None => None,
+    };
+
+    let response = next.run(request).await;
+
+    if !response.status().is_success() {
+        return response;
+    }
+
+    let (entity_type, entity_uuid, after, response) = match target {
+        Some(target) => {
+            let after = snapshot(&ctx, target).await;
+            (
+                Some(target.entity_type()),
+                before.as_ref().or(after.as_ref()).map(|s| s.uuid),
+                after.map(|s| s.value),
+                response,
+            )
+        }
+        // this created something new, record what the api returned
+        None if body_size(response.headers(), response.body()).is_some() => {
+            let (parts, body) = response.into_parts();
+            let body = match to_bytes(body, MAX_BODY_SIZE).await {
+                Ok(body) => body,
+                Err(error) => {
+                    error!(?error, "failed to read response body for audit log");
+                    return GENERIC_SERVER_ERROR.into_response();
+                }
+            };
+            let after = serde_json::from_slice::<Value>(&body).ok().map(redact);
+            // switches only have a uuid `id`
+            let uuid = after
+                .as_ref()
+                .and_then(|v| v["uuid"].as_str().or(v["id"].as_str()))
+                .and_then(|v| Uuid::parse_str(v).ok());
+            (
+                None,
+                uuid,
+                after,
+                Response::from_parts(parts, Body::from(body)),
+            )
+        }
+        None => (None, None, None, response),
+    };
+
+    if let Err(error) = sqlx::query(
+        "insert into audit_log (system, app_id, token_id, method, route, entity_type, entity_uuid, request, diff) values ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+    )
+    .bind(system_id)
+    .bind(auth.app_id())
+    .bind(auth.token_id())
+    .bind(method)
+    .bind(route)
+    .bind(entity_type)
+    .bind(entity_uuid)
+    .bind(request_json)
+    .bind(diff(before.map(|s| s.value), after))
+    .execute(&ctx.db)
+    .await
+    {
+        error!(?error, system_id, "failed to write audit log entry");
+    }
+
+    response
+}
@@ -5,6 +5,7 @@ use axum::{
     response::Response,
 };
 
+use sha2::{Digest, Sha256};
 use subtle::ConstantTimeEq;
 
 use tracing::error;
@@ -12,9 +13,19 @@ use tracing::error;
 use crate::auth::AuthState;
 use crate::{ApiContext, util::json_err};
 
+// legacy tokens don't have ids, so they're told apart by a hash
+// regenerating the token changes it
+fn token_id(token: &str) -> String {
+    Sha256::digest(token.as_bytes())[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
 pub async fn auth(State(ctx): State<ApiContext>, mut req: Request, next: Next) -> Response {
     let mut authed_system_id: Option<i32> = None;
     let mut authed_app_id: Option<i32> = None;
+    let mut authed_token_id: Option<String> = None;
 
     // fetch user authorization
     if let Some(system_auth_header) = req
@@ -35,6 +46,7 @@ pub async fn auth(State(ctx): State<ApiContext>, mut req: Request, next: Next) -
             }
     {
         authed_system_id = Some(system_id);
+        authed_token_id = Some(token_id(system_auth_header));
     }
 
     // fetch app authorization
@@ -68,13 +80,12 @@ pub async fn auth(State(ctx): State<ApiContext>, mut req: Request, next: Next) -
         false
     };
 
-    req.extensions_mut()
-        .insert(AuthState::new(authed_system_id, authed_app_id, internal));
+    let auth = AuthState::new(authed_system_id, authed_app_id, authed_token_id, internal);
+    req.extensions_mut().insert(auth.clone());
 
     let mut res = next.run(req).await;
 
-    res.extensions_mut()
-        .insert(AuthState::new(authed_system_id, authed_app_id, internal));
+    res.extensions_mut().insert(auth);
 
     res
 }
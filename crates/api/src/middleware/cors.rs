@@ -1,28 +1,187 @@
+use std::{collections::HashSet, sync::RwLock, time::Duration};
+
 use axum::{
-    extract::Request,
+    extract::{Request, State},
     http::{HeaderMap, HeaderValue, Method, StatusCode},
     middleware::Next,
     response::{IntoResponse, Response},
 };
+use tracing::error;
+
+use crate::ApiContext;
+
+const APP_ORIGINS_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct CorsPolicy {
+    // these can make credentialed requests, everything else gets a wildcard origin
+    allowed_origins: HashSet<String>,
+    // web origins declared by registered apps, reloaded in `app_origins_loop`
+    app_origins: RwLock<HashSet<String>>,
+    allowed_headers: HeaderValue,
+    max_age: HeaderValue,
+}
+
+// parsed at startup, so that bad config fails there rather than on the first request
+pub fn cors_policy() -> anyhow::Result<CorsPolicy> {
+    let config = libpk::config.api();
+    Ok(CorsPolicy {
+        allowed_origins: parse_origins(config.cors_allowed_origins.as_deref().unwrap_or_default()),
+        app_origins: RwLock::new(HashSet::new()),
+        allowed_headers: HeaderValue::from_str(&config.cors_allowed_headers)
+            .map_err(|_| anyhow::anyhow!("invalid cors_allowed_headers"))?,
+        max_age: HeaderValue::from(config.cors_max_age),
+    })
+}
+
+fn parse_origins(origins: &str) -> HashSet<String> {
+    normalize_origins(origins.split(','))
+}
+
+// browsers send origins without a trailing slash
+fn normalize_origins<'a>(origins: impl Iterator<Item = &'a str>) -> HashSet<String> {
+    origins
+        .map(|o| o.trim().trim_end_matches('/').to_string())
+        .filter(|o| !o.is_empty())
+        .collect()
+}
+
+impl CorsPolicy {
+    fn allowed_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        let origin = origin?;
+        let value = origin.to_str().ok()?;
+        (self.allowed_origins.contains(value) || self.app_origins.read().unwrap().contains(value))
+            .then(|| origin.clone())
+    }
 
-#[rustfmt::skip]
-fn add_cors_headers(headers: &mut HeaderMap) {
-    headers.append("Access-Control-Allow-Origin", HeaderValue::from_static("*"));
-    headers.append("Access-Control-Allow-Methods", HeaderValue::from_static("*"));
-    headers.append("Access-Control-Allow-Credentials", HeaderValue::from_static("true"));
-    headers.append("Access-Control-Allow-Headers", HeaderValue::from_static("Content-Type, Authorization, sentry-trace, User-Agent"));
-    headers.append("Access-Control-Expose-Headers", HeaderValue::from_static("X-PluralKit-Version, X-RateLimit-Limit, X-RateLimit-Remaining, X-RateLimit-Reset, X-RateLimit-Scope"));
-    headers.append("Access-Control-Max-Age", HeaderValue::from_static("86400"));
+    fn set_app_origins(&self, origins: HashSet<String>) {
+        *self.app_origins.write().unwrap() = origins;
+    }
+
+    #[rustfmt::skip]
+    fn add_cors_headers(&self, headers: &mut HeaderMap, allowed_origin: Option<HeaderValue>) {
+        // browsers reject a wildcard origin (and wildcard methods) on credentialed requests,
+        // so we only allow credentials when we can reflect an allowed origin back
+        match allowed_origin {
+            Some(origin) => {
+                headers.append("Access-Control-Allow-Origin", origin);
+                headers.append("Access-Control-Allow-Credentials", HeaderValue::from_static("true"));
+            }
+            None => {
+                headers.append("Access-Control-Allow-Origin", HeaderValue::from_static("*"));
+            }
+        }
+        headers.append("Vary", HeaderValue::from_static("Origin"));
+        headers.append("Access-Control-Allow-Methods", HeaderValue::from_static("GET, POST, PATCH, DELETE, OPTIONS"));
+        headers.append("Access-Control-Allow-Headers", self.allowed_headers.clone());
+        headers.append("Access-Control-Expose-Headers", HeaderValue::from_static("X-PluralKit-Version, X-RateLimit-Limit, X-RateLimit-Remaining, X-RateLimit-Reset, X-RateLimit-Scope"));
+        headers.append("Access-Control-Max-Age", self.max_age.clone());
+    }
 }
 
-pub async fn cors(request: Request, next: Next) -> Response {
+pub async fn cors(State(ctx): State<ApiContext>, request: Request, next: Next) -> Response {
+    let allowed_origin = ctx.cors.allowed_origin(request.headers().get("Origin"));
+
     let mut response = if request.method() == Method::OPTIONS {
         StatusCode::OK.into_response()
     } else {
         next.run(request).await
     };
 
-    add_cors_headers(response.headers_mut());
+    ctx.cors
+        .add_cors_headers(response.headers_mut(), allowed_origin);
 
     response
 }
+
+// apps are registered by hand for now, so checking once a minute is plenty
+pub async fn app_origins_loop(ctx: ApiContext) {
+    loop {
+        match sqlx::query_scalar::<_, String>("select unnest(web_origins) from api_apps")
+            .fetch_all(&ctx.db)
+            .await
+        {
+            Ok(origins) => ctx
+                .cors
+                .set_app_origins(normalize_origins(origins.iter().map(String::as_str))),
+            Err(error) => error!(?error, "failed to fetch app origins"),
+        }
+
+        tokio::time::sleep(APP_ORIGINS_RELOAD_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(origins: &str) -> CorsPolicy {
+        CorsPolicy {
+            allowed_origins: parse_origins(origins),
+            app_origins: RwLock::new(HashSet::new()),
+            allowed_headers: HeaderValue::from_static("Content-Type"),
+            max_age: HeaderValue::from(0),
+        }
+    }
+
+    #[test]
+    fn parses_origin_list() {
+        let origins = parse_origins(" https://a.example, https://b.example/ ,,");
+        assert_eq!(origins.len(), 2);
+        assert!(origins.contains("https://a.example"));
+        assert!(origins.contains("https://b.example"));
+        assert!(parse_origins("").is_empty());
+    }
+
+    #[test]
+    fn reflects_only_allowed_origins() {
+        let policy = policy("https://dash.pluralkit.me");
+
+        let allowed = HeaderValue::from_static("https://dash.pluralkit.me");
+        assert_eq!(policy.allowed_origin(Some(&allowed)), Some(allowed));
+
+        let other = HeaderValue::from_static("https://evil.example");
+        assert_eq!(policy.allowed_origin(Some(&other)), None);
+
+        // origins are matched exactly, not as prefixes
+        let prefixed = HeaderValue::from_static("https://dash.pluralkit.me.evil.example");
+        assert_eq!(policy.allowed_origin(Some(&prefixed)), None);
+
+        assert_eq!(policy.allowed_origin(None), None);
+    }
+
+    #[test]
+    fn reflects_registered_app_origins() {
+        let policy = policy("https://dash.pluralkit.me");
+        let app = HeaderValue::from_static("https://widget.example");
+        assert_eq!(policy.allowed_origin(Some(&app)), None);
+
+        policy.set_app_origins(normalize_origins(["https://widget.example/"].into_iter()));
+        assert_eq!(policy.allowed_origin(Some(&app)), Some(app.clone()));
+        // config origins still work alongside them
+        let dash = HeaderValue::from_static("https://dash.pluralkit.me");
+        assert_eq!(policy.allowed_origin(Some(&dash)), Some(dash));
+
+        // and apps lose access once their origin is removed
+        policy.set_app_origins(HashSet::new());
+        assert_eq!(policy.allowed_origin(Some(&app)), None);
+    }
+
+    #[test]
+    fn credentials_only_with_reflected_origin() {
+        let policy = policy("https://dash.pluralkit.me");
+
+        let mut headers = HeaderMap::new();
+        policy.add_cors_headers(&mut headers, None);
+        assert_eq!(headers["Access-Control-Allow-Origin"], "*");
+        assert!(!headers.contains_key("Access-Control-Allow-Credentials"));
+
+        let mut headers = HeaderMap::new();
+        let origin = HeaderValue::from_static("https://dash.pluralkit.me");
+        policy.add_cors_headers(&mut headers, Some(origin));
+        assert_eq!(
+            headers["Access-Control-Allow-Origin"],
+            "https://dash.pluralkit.me"
+        );
+        assert_eq!(headers["Access-Control-Allow-Credentials"], "true");
+    }
+}
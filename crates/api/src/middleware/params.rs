@@ -1,18 +1,23 @@
 use axum::{
     extract::{Request, State},
-    http::StatusCode,
+    http::Method,
     middleware::Next,
     response::{IntoResponse, Response},
     routing::url_params::UrlParams,
 };
+use metrics::counter;
 use sqlx::types::Uuid;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 use tracing::warn;
 
 use crate::{
     ApiContext,
     auth::{AuthState, Authable},
     error::{self, PKError},
-    util::json_err,
 };
 use pluralkit_models::{GroupId, MemberId, SwitchId, SystemId};
 
@@ -29,13 +34,7 @@ pub async fn params(State(ctx): State<ApiContext>, mut req: Request, next: Next)
     let pms = match req.extensions().get::<UrlParams>() {
         None => Vec::new(),
         Some(UrlParams::Params(pms)) => pms.clone(),
-        _ => {
-            return json_err(
-                StatusCode::BAD_REQUEST,
-                r#"{"message":"400: Bad Request","code": 0}"#.to_string(),
-            )
-            .into();
-        }
+        _ => return error::GENERIC_BAD_REQUEST.into_response(),
     };
 
     for (key, value) in pms {
@@ -50,42 +49,29 @@ pub async fn params(State(ctx): State<ApiContext>, mut req: Request, next: Next)
                     .system_id()
                 {
                     Some(system_id) => system_id,
-                    _ => {
-                        return json_err(
-                                        StatusCode::UNAUTHORIZED,
-                                        r#"{"message":"401: Missing or invalid Authorization header","code": 0}"#
-                                            .to_string(),
-                                    )
-                                    .into();
-                    }
+                    _ => return error::GENERIC_AUTH_ERROR.into_response(),
                 };
 
                 Ok(Some(RequestAbout::System(system_id)))
             }
             "system_id" if Uuid::parse_str(id_ref).is_ok() => {
-                resolve_entity(&ctx.db, "systems", "uuid", id_ref).await
+                resolve_entity(&ctx, "systems", "uuid", id_ref).await
             }
             "system_id" if let Ok(discord_id) = id_ref.parse::<i64>() => {
-                sqlx::query_as::<_, ResolveEntityRow>(
-                    "select 0 as id, system from accounts where uid = $1",
-                )
-                .bind(discord_id)
-                .fetch_optional(&ctx.db)
-                .await
-                .map_err(PKError::from)
-                .and_then(|v| v.and_then(|row| row.system).ok_or(error::SYSTEM_NOT_FOUND))
-                .map(|system| Some(RequestAbout::System(system)))
+                resolve_account(&ctx, discord_id).await
             }
-            "system_id" => resolve_entity(&ctx.db, "systems", "hid", id_ref).await,
+            "system_id" => resolve_entity(&ctx, "systems", "hid", id_ref).await,
             "member_id" if Uuid::parse_str(id_ref).is_ok() => {
-                resolve_entity(&ctx.db, "members", "uuid", id_ref).await
+                resolve_entity(&ctx, "members", "uuid", id_ref).await
             }
-            "member_id" => resolve_entity(&ctx.db, "members", "hid", id_ref).await,
+            "member_id" => resolve_entity(&ctx, "members", "hid", id_ref).await,
             "group_id" if Uuid::parse_str(id_ref).is_ok() => {
-                resolve_entity(&ctx.db, "groups", "uuid", id_ref).await
+                resolve_entity(&ctx, "groups", "uuid", id_ref).await
             }
-            "group_id" => resolve_entity(&ctx.db, "groups", "hid", id_ref).await,
-            "switch_id" => resolve_entity(&ctx.db, "switches", "uuid", id_ref).await,
+            "group_id" => resolve_entity(&ctx, "groups", "hid", id_ref).await,
+            "switch_id" => resolve_entity(&ctx, "switches", "uuid", id_ref).await,
+            // resolved by the handler (admin endpoints)
+            "account_id" | "abuse_log_id" => Ok(None),
             _ => {
                 warn!("unmatched request param {key}");
                 Ok(None)
@@ -101,7 +87,22 @@ pub async fn params(State(ctx): State<ApiContext>, mut req: Request, next: Next)
         }
     }
 
-    next.run(req).await
+    // if we're deleting something, we need to find its cache keys before it's gone
+    let invalidate_keys = if req.method() == Method::DELETE
+        && let Some(about) = req.extensions().get::<RequestAbout>()
+    {
+        entity_cache_keys(&ctx, about).await
+    } else {
+        Vec::new()
+    };
+
+    let response = next.run(req).await;
+
+    if response.status().is_success() && !invalidate_keys.is_empty() {
+        ctx.entity_cache.remove(invalidate_keys);
+    }
+
+    response
 }
 
 #[allow(dead_code)]
@@ -122,6 +123,16 @@ impl RequestAbout {
             Self::Switch { system, .. } => *system,
         }
     }
+
+    /// database table and id of the entity this request is about
+    pub fn entity(&self) -> (&'static str, i32) {
+        match self {
+            Self::System(id) => ("systems", *id),
+            Self::Member { id, .. } => ("members", *id),
+            Self::Group { id, .. } => ("groups", *id),
+            Self::Switch { id, .. } => ("switches", *id),
+        }
+    }
 }
 
 impl Authable for RequestAbout {
@@ -130,14 +141,131 @@ impl Authable for RequestAbout {
     }
 }
 
-#[derive(sqlx::FromRow)]
+#[derive(sqlx::FromRow, Clone, Copy)]
 struct ResolveEntityRow {
     id: i32,
     system: Option<i32>,
 }
 
+// this is the first db query on every request, so we cache it
+// the bot changes and deletes entities without going through the api, so entries have to expire
+// quickly, and this is kept in-process so there's nothing to invalidate across services
+const ENTITY_CACHE_TTL: Duration = Duration::from_secs(10);
+const ENTITY_CACHE_SIZE: usize = 100_000;
+
+pub struct EntityCache {
+    entries: Mutex<HashMap<String, (Instant, ResolveEntityRow)>>,
+}
+
+pub fn entity_cache() -> EntityCache {
+    EntityCache {
+        entries: Mutex::new(HashMap::new()),
+    }
+}
+
+impl EntityCache {
+    fn get(&self, key: &str) -> Option<ResolveEntityRow> {
+        let res = self
+            .entries
+            .lock()
+            .expect("entity cache lock poisoned")
+            .get(key)
+            .filter(|(at, _)| at.elapsed() < ENTITY_CACHE_TTL)
+            .map(|(_, row)| *row);
+
+        let result = if res.is_some() { "hit" } else { "miss" };
+        counter!("pluralkit_api_entity_cache", "result" => result).increment(1);
+        res
+    }
+
+    fn set(&self, key: String, row: ResolveEntityRow) {
+        let mut entries = self.entries.lock().expect("entity cache lock poisoned");
+        if entries.len() >= ENTITY_CACHE_SIZE {
+            entries.retain(|_, (at, _)| at.elapsed() < ENTITY_CACHE_TTL);
+            // everything is still fresh, start over rather than tracking recency
+            if entries.len() >= ENTITY_CACHE_SIZE {
+                entries.clear();
+            }
+        }
+        entries.insert(key, (Instant::now(), row));
+    }
+
+    fn remove(&self, keys: Vec<String>) {
+        let mut entries = self.entries.lock().expect("entity cache lock poisoned");
+        for key in keys {
+            entries.remove(&key);
+        }
+    }
+}
+
+fn entity_cache_key(table: &str, column: &str, value: &str) -> String {
+    // uuids can be passed in a few different formats
+    let value = match column {
+        "uuid" => Uuid::parse_str(value)
+            .map(|v| v.to_string())
+            .unwrap_or(value.to_string()),
+        _ => value.to_string(),
+    };
+    format!("{table}:{column}:{value}")
+}
+
+#[derive(sqlx::FromRow)]
+struct EntityRefs {
+    hid: Option<String>,
+    uuid: String,
+}
+
+async fn entity_cache_keys(ctx: &ApiContext, about: &RequestAbout) -> Vec<String> {
+    let (table, id) = about.entity();
+    let hid_col = if table == "switches" {
+        "null::text as hid"
+    } else {
+        "hid"
+    };
+
+    match sqlx::query_as::<_, EntityRefs>(
+        format!("select {hid_col}, uuid::text as uuid from {table} where id = $1").as_str(),
+    )
+    .bind(id)
+    .fetch_optional(&ctx.db)
+    .await
+    {
+        Ok(Some(refs)) => {
+            let mut keys = vec![entity_cache_key(table, "uuid", refs.uuid.as_str())];
+            if let Some(hid) = refs.hid {
+                keys.push(entity_cache_key(table, "hid", hid.trim()));
+            }
+            keys
+        }
+        Ok(None) => Vec::new(),
+        Err(error) => {
+            warn!(?error, table, id, "failed to fetch entity cache keys");
+            Vec::new()
+        }
+    }
+}
+
+// not cached: accounts get linked and unlinked from the bot, and a stale entry here
+// would resolve to the wrong system
+async fn resolve_account(
+    ctx: &ApiContext,
+    discord_id: i64,
+) -> Result<Option<RequestAbout>, PKError> {
+    let row = sqlx::query_as::<_, ResolveEntityRow>(
+        "select 0 as id, system from accounts where uid = $1",
+    )
+    .bind(discord_id)
+    .fetch_optional(&ctx.db)
+    .await
+    .map_err(PKError::from)?;
+
+    row.and_then(|row| row.system)
+        .ok_or(error::SYSTEM_NOT_FOUND)
+        .map(|system| Some(RequestAbout::System(system)))
+}
+
 async fn resolve_entity(
-    pool: &sqlx::postgres::PgPool,
+    ctx: &ApiContext,
     table: &str,
     column: &str,
     value: &str,
@@ -154,14 +282,29 @@ async fn resolve_entity(
         _ => "$1",
     };
 
-    let Some(row): Option<ResolveEntityRow> = sqlx::query_as(
-        format!("select id, {system_col} from {table} where {column} = {maybe_cast}").as_str(),
-    )
-    .bind(value)
-    .fetch_optional(pool)
-    .await
-    .map_err(PKError::from)?
-    else {
+    let cache_key = entity_cache_key(table, column, value);
+
+    let row = match ctx.entity_cache.get(&cache_key) {
+        Some(row) => Some(row),
+        None => {
+            let row: Option<ResolveEntityRow> = sqlx::query_as(
+                format!("select id, {system_col} from {table} where {column} = {maybe_cast}")
+                    .as_str(),
+            )
+            .bind(value)
+            .fetch_optional(&ctx.db)
+            .await
+            .map_err(PKError::from)?;
+
+            if let Some(row) = row {
+                ctx.entity_cache.set(cache_key, row);
+            }
+
+            row
+        }
+    };
+
+    let Some(row) = row else {
         return Err(match table {
             "systems" => error::SYSTEM_NOT_FOUND,
             "members" => error::MEMBER_NOT_FOUND,
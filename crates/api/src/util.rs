@@ -3,7 +3,7 @@ use axum::{
     http::{HeaderValue, StatusCode},
     response::IntoResponse,
 };
-use serde_json::{Value, json, to_string};
+use serde_json::{Value, to_string};
 use tracing::error;
 
 pub fn header_or_unknown(header: Option<&HeaderValue>) -> &str {
@@ -28,11 +28,7 @@ where
     move || match handler() {
         Ok(v) => (StatusCode::OK, to_string(&v).unwrap()).into_response(),
         Err(error) => match error.downcast_ref::<PKError>() {
-            Some(pkerror) => json_err(
-                pkerror.response_code,
-                to_string(&json!({ "message": pkerror.message, "code": pkerror.json_code }))
-                    .unwrap(),
-            ),
+            Some(pkerror) => json_err(pkerror.response_code, to_string(&pkerror.to_json()).unwrap()),
             None => {
                 error!(?error, "error in handler {}", std::any::type_name::<F>(),);
                 json_err(
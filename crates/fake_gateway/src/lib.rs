@@ -0,0 +1,405 @@
+// a fake discord gateway, for testing the gateway service without connecting to discord
+//
+// point the gateway at it with `pluralkit__discord__gateway_proxy_url=ws://localhost:5010`
+// it speaks enough of the protocol for twilight shards to identify, heartbeat and resume,
+// and runs the scripted scenario from `FAKE_GATEWAY_SCENARIO` (see scenario.rs)
+//
+// `GET /identifies` lists every IDENTIFY received, to check the identify queue's pacing
+//
+// this is also a library, so the gateway's tests can run it in-process
+
+use axum::{
+    Json, Router,
+    extract::{
+        Query, State,
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use flate2::{Compression, write::ZlibEncoder};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Write,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::Mutex, time::Instant};
+use tracing::{info, warn};
+
+use scenario::{Action, Scenario, Step, guild_id};
+
+pub mod scenario;
+
+// discord allows one identify per 5 seconds per bucket
+const IDENTIFY_INTERVAL_MS: u64 = 5_000;
+
+struct Session {
+    shard_id: u32,
+    seq: u64,
+}
+
+#[derive(Serialize, Clone)]
+struct Identify {
+    shard_id: u32,
+    bucket: u32,
+    /// unix millis
+    at: u64,
+}
+
+struct AppState {
+    scenario: Scenario,
+    url: String,
+    user_id: String,
+    max_concurrency: u32,
+    sessions: Mutex<HashMap<String, Session>>,
+    // how far into the scenario each shard is
+    progress: Mutex<HashMap<u32, usize>>,
+    identifies: Mutex<Vec<Identify>>,
+    session_counter: AtomicU64,
+}
+
+pub struct Settings {
+    pub scenario: Scenario,
+    // sent as the resume url
+    pub url: String,
+    pub user_id: String,
+    pub max_concurrency: u32,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+pub fn router(settings: Settings) -> Router {
+    let state = Arc::new(AppState {
+        scenario: settings.scenario,
+        url: settings.url,
+        user_id: settings.user_id,
+        max_concurrency: settings.max_concurrency,
+        sessions: Mutex::new(HashMap::new()),
+        progress: Mutex::new(HashMap::new()),
+        identifies: Mutex::new(Vec::new()),
+        session_counter: AtomicU64::new(0),
+    });
+
+    Router::new()
+        .route("/", get(gateway))
+        .route(
+            "/identifies",
+            get(|State(state): State<Arc<AppState>>| async move {
+                Json(state.identifies.lock().await.clone())
+            }),
+        )
+        .with_state(state)
+}
+
+async fn gateway(
+    ws: WebSocketUpgrade,
+    Query(params): Query<HashMap<String, String>>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let encoder = match params.get("compress").map(String::as_str) {
+        None => None,
+        Some("zlib-stream") => Some(ZlibEncoder::new(Vec::new(), Compression::fast())),
+        Some(other) => {
+            warn!(compress = other, "unsupported transport compression");
+            return (StatusCode::BAD_REQUEST, "").into_response();
+        }
+    };
+
+    ws.on_upgrade(move |socket| async move {
+        let mut conn = Connection {
+            socket,
+            state,
+            encoder,
+            session_id: None,
+            acking: true,
+            steps: VecDeque::new(),
+            next_step_at: None,
+        };
+        if let Err(error) = conn.run().await {
+            warn!(?error, "connection ended with an error");
+        }
+    })
+}
+
+struct Connection {
+    socket: WebSocket,
+    state: Arc<AppState>,
+    encoder: Option<ZlibEncoder<Vec<u8>>>,
+    session_id: Option<String>,
+    acking: bool,
+    steps: VecDeque<(u32, Step)>,
+    next_step_at: Option<Instant>,
+}
+
+impl Connection {
+    async fn run(&mut self) -> anyhow::Result<()> {
+        self.send(json!({
+            "op": 10,
+            "d": { "heartbeat_interval": self.state.scenario.heartbeat_interval },
+        }))
+        .await?;
+
+        loop {
+            let next_step_at = self.next_step_at;
+            let next_step = async move {
+                match next_step_at {
+                    Some(at) => tokio::time::sleep_until(at).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                msg = self.socket.recv() => match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        self.handle(serde_json::from_str(text.as_str())?).await?
+                    }
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => {}
+                    Some(Err(error)) => return Err(error.into()),
+                },
+                _ = next_step => {
+                    if !self.run_step().await? {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send(&mut self, payload: Value) -> anyhow::Result<()> {
+        let text = payload.to_string();
+        let msg = match self.encoder.as_mut() {
+            // zlib-stream is a single deflate stream, with every payload ending in a sync flush
+            Some(encoder) => {
+                encoder.write_all(text.as_bytes())?;
+                encoder.flush()?;
+                Message::Binary(std::mem::take(encoder.get_mut()).into())
+            }
+            None => Message::Text(text.into()),
+        };
+        self.socket.send(msg).await?;
+        Ok(())
+    }
+
+    async fn dispatch(&mut self, t: &str, d: Value) -> anyhow::Result<()> {
+        let seq = match self.session_id.as_ref() {
+            Some(session_id) => {
+                let mut sessions = self.state.sessions.lock().await;
+                match sessions.get_mut(session_id) {
+                    Some(session) => {
+                        session.seq += 1;
+                        session.seq
+                    }
+                    None => 0,
+                }
+            }
+            None => 0,
+        };
+
+        self.send(json!({ "op": 0, "t": t, "s": seq, "d": d }))
+            .await
+    }
+
+    async fn handle(&mut self, payload: Value) -> anyhow::Result<()> {
+        match payload["op"].as_u64() {
+            // heartbeat
+            Some(1) => {
+                if self.acking {
+                    self.send(json!({ "op": 11 })).await?;
+                }
+            }
+            Some(2) => self.identify(&payload["d"]).await?,
+            Some(6) => self.resume(&payload["d"]).await?,
+            // presence updates, member requests, ...
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn identify(&mut self, d: &Value) -> anyhow::Result<()> {
+        let shard_id = d["shard"][0].as_u64().unwrap_or(0) as u32;
+        let total_shards = d["shard"][1].as_u64().unwrap_or(1) as u32;
+        let bucket = shard_id % self.state.max_concurrency;
+
+        {
+            let mut identifies = self.state.identifies.lock().await;
+            let at = now_ms();
+            // saturating, in case the wall clock stepped backwards
+            if let Some(last) = identifies.iter().rev().find(|i| i.bucket == bucket)
+                && at.saturating_sub(last.at) < IDENTIFY_INTERVAL_MS
+            {
+                warn!(
+                    shard_id,
+                    bucket,
+                    "identified {}ms after the previous identify in its bucket",
+                    at.saturating_sub(last.at)
+                );
+            }
+            identifies.push(Identify {
+                shard_id,
+                bucket,
+                at,
+            });
+        }
+
+        let session_id = format!(
+            "fake-{shard_id}-{}",
+            self.state.session_counter.fetch_add(1, Ordering::Relaxed)
+        );
+        self.state
+            .sessions
+            .lock()
+            .await
+            .insert(session_id.clone(), Session { shard_id, seq: 0 });
+        self.session_id = Some(session_id.clone());
+        info!(shard_id, total_shards, session_id, "shard identified");
+
+        let guilds = self.state.scenario.guilds_for(shard_id, total_shards);
+        let unavailable: Vec<Value> = guilds
+            .iter()
+            .filter_map(guild_id)
+            .map(|id| json!({ "id": id.to_string(), "unavailable": true }))
+            .collect();
+
+        self.dispatch(
+            "READY",
+            json!({
+                "v": 10,
+                "user": {
+                    "id": self.state.user_id,
+                    "username": "PluralKit",
+                    "discriminator": "0",
+                    "global_name": null,
+                    "avatar": null,
+                    "bot": true,
+                    "mfa_enabled": false,
+                },
+                "guilds": unavailable,
+                "session_id": session_id,
+                "resume_gateway_url": self.state.url,
+                "shard": [shard_id, total_shards],
+                "application": { "id": self.state.user_id, "flags": 0 },
+            }),
+        )
+        .await?;
+
+        for guild in guilds {
+            self.dispatch("GUILD_CREATE", guild).await?;
+        }
+
+        self.start_steps(shard_id).await;
+        Ok(())
+    }
+
+    async fn resume(&mut self, d: &Value) -> anyhow::Result<()> {
+        let session_id = d["session_id"].as_str().unwrap_or_default().to_string();
+        let shard_id = self
+            .state
+            .sessions
+            .lock()
+            .await
+            .get(&session_id)
+            .map(|s| s.shard_id);
+
+        let Some(shard_id) = shard_id else {
+            info!(session_id, "resume for unknown session");
+            return self.send(json!({ "op": 9, "d": false })).await;
+        };
+
+        self.session_id = Some(session_id.clone());
+        info!(shard_id, session_id, "shard resumed");
+        self.dispatch("RESUMED", Value::Null).await?;
+
+        self.start_steps(shard_id).await;
+        Ok(())
+    }
+
+    async fn start_steps(&mut self, shard_id: u32) {
+        let done = *self
+            .state
+            .progress
+            .lock()
+            .await
+            .get(&shard_id)
+            .unwrap_or(&0);
+        self.steps = self
+            .state
+            .scenario
+            .steps_for(shard_id)
+            .into_iter()
+            .skip(done)
+            .map(|step| (shard_id, step))
+            .collect();
+        self.schedule_next_step();
+    }
+
+    fn schedule_next_step(&mut self) {
+        self.next_step_at = self
+            .steps
+            .front()
+            .map(|(_, step)| Instant::now() + Duration::from_millis(step.after_ms));
+    }
+
+    /// returns false if the step closed the connection
+    async fn run_step(&mut self) -> anyhow::Result<bool> {
+        let Some((shard_id, step)) = self.steps.pop_front() else {
+            self.next_step_at = None;
+            return Ok(true);
+        };
+
+        // counted before running, so a step that closes the connection isn't repeated
+        *self
+            .state
+            .progress
+            .lock()
+            .await
+            .entry(shard_id)
+            .or_default() += 1;
+        info!(shard_id, ?step.action, "running scenario step");
+
+        match step.action {
+            Action::Close { code } => {
+                if let 4007 | 4009 = code {
+                    self.end_session().await;
+                }
+                self.socket
+                    .send(Message::Close(Some(CloseFrame {
+                        code,
+                        reason: "".into(),
+                    })))
+                    .await?;
+                return Ok(false);
+            }
+            Action::Reconnect => self.send(json!({ "op": 7, "d": null })).await?,
+            Action::InvalidSession { resumable } => {
+                if !resumable {
+                    self.end_session().await;
+                }
+                self.send(json!({ "op": 9, "d": resumable })).await?;
+            }
+            Action::StopAcks => self.acking = false,
+            Action::Dispatch { t, d } => self.dispatch(&t, d).await?,
+        }
+
+        self.schedule_next_step();
+        Ok(true)
+    }
+
+    async fn end_session(&mut self) {
+        if let Some(session_id) = self.session_id.take() {
+            self.state.sessions.lock().await.remove(&session_id);
+        }
+    }
+}
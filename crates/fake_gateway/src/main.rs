@@ -0,0 +1,25 @@
+use fake_gateway::{Settings, router, scenario::Scenario};
+use tracing::info;
+
+fn env_or(key: &str, default: &str) -> String {
+    std::env::var(key).unwrap_or(default.to_string())
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let addr = env_or("FAKE_GATEWAY_ADDR", "[::]:5010");
+    let app = router(Settings {
+        scenario: Scenario::load()?,
+        url: env_or("FAKE_GATEWAY_URL", "ws://localhost:5010"),
+        user_id: env_or("FAKE_GATEWAY_USER_ID", "466378653216014359"),
+        max_concurrency: env_or("FAKE_GATEWAY_MAX_CONCURRENCY", "1").parse()?,
+    });
+
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    info!("listening on {}", addr);
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+fn _default_heartbeat_interval() -> u64 {
+    41_250
+}
+
+/// what the fake gateway should do, loaded from the json file in `FAKE_GATEWAY_SCENARIO`
+///
+/// ```json
+/// {
+///   "heartbeat_interval": 5000,
+///   "guilds": [{ "id": "466707357099884544", ... }],
+///   "steps": [
+///     { "after_ms": 2000, "action": "close", "code": 4000 },
+///     { "after_ms": 1000, "action": "stop_acks", "shard": 1 },
+///     { "after_ms": 0, "action": "dispatch", "t": "MESSAGE_CREATE", "d": { ... } }
+///   ]
+/// }
+/// ```
+#[derive(Deserialize)]
+pub struct Scenario {
+    #[serde(default = "_default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+
+    /// full guild objects, sent as GUILD_CREATE to the shard they belong to after READY
+    #[serde(default)]
+    pub guilds: Vec<Value>,
+
+    /// run in order for each shard, continuing across reconnects
+    /// a step's delay starts when the shard is ready (or resumed), or after the previous step
+    #[serde(default)]
+    pub steps: Vec<Step>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Step {
+    #[serde(default)]
+    pub after_ms: u64,
+
+    /// only run this step on one shard, otherwise every shard runs it
+    #[serde(default)]
+    pub shard: Option<u32>,
+
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    /// close the socket, 4007 and 4009 also end the session like discord does
+    Close {
+        code: u16,
+    },
+    /// op 7, ask the client to reconnect and resume
+    Reconnect,
+    /// op 9
+    InvalidSession {
+        resumable: bool,
+    },
+    /// stop ACKing heartbeats until the next connection, to look like a zombied connection
+    StopAcks,
+    Dispatch {
+        t: String,
+        d: Value,
+    },
+}
+
+impl Scenario {
+    pub fn load() -> anyhow::Result<Scenario> {
+        match std::env::var("FAKE_GATEWAY_SCENARIO") {
+            Ok(path) => Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?),
+            // nothing scripted, just stay connected
+            Err(_) => Ok(serde_json::from_str("{}")?),
+        }
+    }
+
+    pub fn steps_for(&self, shard_id: u32) -> Vec<Step> {
+        self.steps
+            .iter()
+            .filter(|step| step.shard.is_none_or(|id| id == shard_id))
+            .cloned()
+            .collect()
+    }
+
+    pub fn guilds_for(&self, shard_id: u32, total_shards: u32) -> Vec<Value> {
+        self.guilds
+            .iter()
+            .filter(|guild| {
+                guild_id(guild)
+                    .is_some_and(|id| (id >> 22) % total_shards as u64 == shard_id as u64)
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+pub fn guild_id(guild: &Value) -> Option<u64> {
+    guild.get("id")?.as_str()?.parse().ok()
+}
@@ -15,6 +15,7 @@ use crate::{
         cache::{DM_PERMISSIONS, DiscordCache, dm_channel},
         gateway::cluster_config,
         shard_state::ShardStateManager,
+        supervisor::ShardSupervisor,
     },
     event_awaiter::{AwaitEventRequest, EventAwaiter},
 };
@@ -24,13 +25,48 @@ fn status_code(code: StatusCode, body: String) -> Response {
     (code, body).into_response()
 }
 
+// these don't need the cache, so they can be served on their own in tests
+#[rustfmt::skip]
+fn awaiter_routes<S: Clone + Send + Sync + 'static>(awaiter: Arc<EventAwaiter>) -> Router<S> {
+    // hacky fix for `move`
+    let awaiter_for_clear = awaiter.clone();
+    let awaiter_for_cancel = awaiter.clone();
+
+    Router::new()
+        .route("/await_event", post(|ConnectInfo(addr): ConnectInfo<SocketAddr>, body: String| async move {
+            info!("got request: {body} from: {addr}");
+            let Ok(req) = serde_json::from_str::<AwaitEventRequest>(&body) else {
+                return status_code(StatusCode::BAD_REQUEST, "".to_string());
+            };
+
+            match awaiter.handle_request(req, addr).await {
+                Ok(id) => status_code(StatusCode::OK, json!({ "id": id }).to_string()),
+                Err(error) => {
+                    info!(?error, "invalid await_event request");
+                    status_code(StatusCode::BAD_REQUEST, "".to_string())
+                }
+            }
+        }))
+        .route("/await_event/{id}", delete(|Path(id): Path<String>| async move {
+            if awaiter_for_cancel.cancel(&id).await {
+                status_code(StatusCode::NO_CONTENT, "".to_string())
+            } else {
+                status_code(StatusCode::NOT_FOUND, "".to_string())
+            }
+        }))
+        .route("/clear_awaiter", post(|| async move {
+            awaiter_for_clear.clear().await;
+            status_code(StatusCode::NO_CONTENT, "".to_string())
+        }))
+}
+
 // this function is manually formatted for easier legibility of route_services
 #[rustfmt::skip]
-pub async fn run_server(cache: Arc<DiscordCache>, shard_state: Arc<ShardStateManager>, runtime_config: Arc<RuntimeConfig>, awaiter: Arc<EventAwaiter>) -> anyhow::Result<()> {
+pub async fn run_server(cache: Arc<DiscordCache>, shard_state: Arc<ShardStateManager>, runtime_config: Arc<RuntimeConfig>, awaiter: Arc<EventAwaiter>, supervisor: Arc<ShardSupervisor>) -> anyhow::Result<()> {
     // hacky fix for `move`
     let runtime_config_for_post = runtime_config.clone();
     let runtime_config_for_delete = runtime_config.clone();
-    let awaiter_for_clear = awaiter.clone();
+    let supervisor_for_reshard = supervisor.clone();
 
     let app = Router::new()
         .route(
@@ -133,7 +169,18 @@ pub async fn run_server(cache: Arc<DiscordCache>, shard_state: Arc<ShardStateMan
         )
         .route(
             "/guilds/{guild_id}/channels/{channel_id}/permissions/{user_id}",
-            get(|| async { "todo" }),
+            get(|State(cache): State<Arc<DiscordCache>>, Path((guild_id, channel_id, user_id)): Path<(u64, u64, u64)>| async move {
+                if guild_id == 0 {
+                    return status_code(StatusCode::FOUND, to_string(&*DM_PERMISSIONS).unwrap());
+                }
+                match cache.channel_permissions(Id::new(channel_id), Id::new(user_id)).await {
+                    Ok(val) => status_code(StatusCode::FOUND, to_string(&val).unwrap()),
+                    Err(err) => {
+                        error!(?err, ?channel_id, ?guild_id, ?user_id, "failed to get channel member permissions");
+                        status_code(StatusCode::INTERNAL_SERVER_ERROR, "".to_string())
+                    },
+                }
+            }),
         )
         .route(
             "/guilds/{guild_id}/channels/{channel_id}/last_message",
@@ -143,6 +190,34 @@ pub async fn run_server(cache: Arc<DiscordCache>, shard_state: Arc<ShardStateMan
             }),
         )
 
+        .route(
+            "/guilds/{guild_id}/threads",
+            get(|State(cache): State<Arc<DiscordCache>>, Path(guild_id): Path<u64>| async move {
+                match cache.threads(Id::new(guild_id)) {
+                    Some(threads) => status_code(StatusCode::FOUND, to_string(&threads).unwrap()),
+                    None => status_code(StatusCode::NOT_FOUND, "".to_string()),
+                }
+            }),
+        )
+        .route(
+            "/guilds/{guild_id}/emojis",
+            get(|State(cache): State<Arc<DiscordCache>>, Path(guild_id): Path<u64>| async move {
+                match cache.emojis(Id::new(guild_id)) {
+                    Some(emojis) => status_code(StatusCode::FOUND, to_string(&emojis).unwrap()),
+                    None => status_code(StatusCode::NOT_FOUND, "".to_string()),
+                }
+            }),
+        )
+        .route(
+            "/guilds/{guild_id}/stickers",
+            get(|State(cache): State<Arc<DiscordCache>>, Path(guild_id): Path<u64>| async move {
+                match cache.stickers(Id::new(guild_id)) {
+                    Some(stickers) => status_code(StatusCode::FOUND, to_string(&stickers).unwrap()),
+                    None => status_code(StatusCode::NOT_FOUND, "".to_string()),
+                }
+            }),
+        )
+
         .route(
             "/guilds/{guild_id}/roles",
             get(|State(cache): State<Arc<DiscordCache>>, Path(guild_id): Path<u64>| async move {
@@ -179,6 +254,8 @@ pub async fn run_server(cache: Arc<DiscordCache>, shard_state: Arc<ShardStateMan
                 "channel_count": stats.channels(),
                 // just put this here until prom stats
                 "unavailable_guild_count": stats.unavailable_guilds(),
+                // restored from a snapshot, and not refreshed yet
+                "stale_guild_count": cache.4.read().await.len(),
                 "up": has_been_up,
             });
             status_code(StatusCode::FOUND, to_string(&stats).unwrap())
@@ -198,24 +275,42 @@ pub async fn run_server(cache: Arc<DiscordCache>, shard_state: Arc<ShardStateMan
             status_code(StatusCode::FOUND, to_string(&runtime_config.get_all().await).unwrap())
         }))
 
-        .route("/await_event", post(|ConnectInfo(addr): ConnectInfo<SocketAddr>, body: String| async move {
-            info!("got request: {body} from: {addr}");
-            let Ok(req) = serde_json::from_str::<AwaitEventRequest>(&body) else {
-                return status_code(StatusCode::BAD_REQUEST, "".to_string());
-            };
+        .merge(awaiter_routes(awaiter))
 
-            awaiter.handle_request(req, addr).await;
-            status_code(StatusCode::NO_CONTENT, "".to_string())
+        .route("/shard_status", get(|| async move {
+            status_code(StatusCode::FOUND, to_string(&shard_state.get().await).unwrap())
         }))
-        .route("/clear_awaiter", post(|| async move {
-            awaiter_for_clear.clear().await;
-            status_code(StatusCode::NO_CONTENT, "".to_string())
+        .route("/shard_status/{shard_id}/restart", post(|Path(shard_id): Path<u32>| async move {
+            match supervisor.restart(shard_id, "manual").await {
+                Ok(_) => status_code(StatusCode::NO_CONTENT, "".to_string()),
+                Err(error) => {
+                    error!(?error, ?shard_id, "failed to restart shard");
+                    status_code(StatusCode::NOT_FOUND, "".to_string())
+                }
+            }
         }))
 
-        .route("/shard_status", get(|| async move {
-            status_code(StatusCode::FOUND, to_string(&shard_state.get().await).unwrap())
+        .route("/reshard/{total_shards}", post(|Path(total_shards): Path<u32>| async move {
+            if total_shards == 0 {
+                return status_code(StatusCode::BAD_REQUEST, "".to_string());
+            }
+            if let Err(error) = crate::discord::gateway::check_reshard(total_shards) {
+                return status_code(StatusCode::BAD_REQUEST, error.to_string());
+            }
+            if supervisor_for_reshard.is_resharding() {
+                return status_code(StatusCode::CONFLICT, "".to_string());
+            }
+
+            // this waits for the new shards to identify, so don't hold the request open
+            tokio::spawn(async move {
+                if let Err(error) = supervisor_for_reshard.reshard(total_shards).await {
+                    error!(?error, ?total_shards, "failed to reshard");
+                }
+            });
+            status_code(StatusCode::ACCEPTED, "".to_string())
         }))
 
+        .layer(axum::middleware::from_fn(crate::auth::auth))
         .layer(axum::middleware::from_fn(crate::logger::logger))
         .with_state(cache);
 
@@ -226,3 +321,49 @@ pub async fn run_server(cache: Arc<DiscordCache>, shard_state: Arc<ShardStateMan
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // serves the awaiter routes on their own, returns the base url
+    async fn serve(awaiter: Arc<EventAwaiter>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = awaiter_routes::<()>(awaiter);
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn cancels_await_event_registrations() {
+        crate::test_util::init_config();
+        let url = serve(Arc::new(EventAwaiter::new(None))).await;
+        let client = reqwest::Client::new();
+
+        let body = client
+            .post(format!("{url}/await_event"))
+            .body(json!({ "channel_id": "10", "target": "source-addr" }).to_string())
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let id = serde_json::from_str::<serde_json::Value>(&body).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let cancel = || client.delete(format!("{url}/await_event/{id}")).send();
+        assert_eq!(cancel().await.unwrap().status(), StatusCode::NO_CONTENT);
+        // already gone
+        assert_eq!(cancel().await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,41 @@
+use axum::{
+    extract::Request,
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use subtle::ConstantTimeEq;
+use tracing::warn;
+
+// anything that isn't a plain read can change where events go, or drop awaiters
+fn needs_auth(request: &Request) -> bool {
+    request.method() != Method::GET || libpk::config.discord().cache_api_auth_reads
+}
+
+pub async fn auth(request: Request, next: Next) -> Response {
+    // selfhosted instances usually don't set this, and rely on the port not being exposed
+    let Some(real_token) = libpk::config.internal_auth.as_ref() else {
+        return next.run(request).await;
+    };
+
+    if !needs_auth(&request) {
+        return next.run(request).await;
+    }
+
+    let authed = request
+        .headers()
+        .get("x-pluralkit-internalauth")
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|token| token.as_bytes().ct_eq(real_token.as_bytes()).into());
+
+    if !authed {
+        warn!(
+            method = %request.method(),
+            uri = %request.uri(),
+            "rejected unauthenticated request to gateway api"
+        );
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    next.run(request).await
+}
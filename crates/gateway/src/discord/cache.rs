@@ -1,7 +1,12 @@
 use anyhow::format_err;
 use lazy_static::lazy_static;
 use serde::Serialize;
-use std::{collections::HashMap, sync::Arc};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
 use tokio::sync::RwLock;
 use twilight_cache_inmemory::{
     InMemoryCache, ResourceType,
@@ -11,15 +16,21 @@ use twilight_cache_inmemory::{
 };
 use twilight_gateway::Event;
 use twilight_model::{
-    channel::{Channel, ChannelType},
-    guild::{Guild, Member, Permissions},
+    channel::{Channel, ChannelType, Message, message::Sticker},
+    gateway::payload::incoming::{GuildDelete, UserUpdate},
+    guild::{Emoji, Guild, Member, Permissions},
     id::{
         Id,
-        marker::{ChannelMarker, GuildMarker, MessageMarker, UserMarker},
+        marker::{ChannelMarker, GuildMarker, MessageMarker, UserMarker, WebhookMarker},
     },
 };
 use twilight_util::permission_calculator::PermissionCalculator;
 
+use super::{
+    gateway::cluster_config,
+    member_cache::{self, MemberCache},
+};
+
 lazy_static! {
     pub static ref DM_PERMISSIONS: Permissions = Permissions::VIEW_CHANNEL
         | Permissions::SEND_MESSAGES
@@ -100,15 +111,23 @@ pub fn new() -> DiscordCache {
 
     let client = Arc::new(client_builder.build());
 
+    let mut resource_types = ResourceType::GUILD
+        | ResourceType::CHANNEL
+        | ResourceType::ROLE
+        | ResourceType::USER_CURRENT
+        | ResourceType::MEMBER_CURRENT;
+
+    // threads are channels, so they're always cached, and `cache_threads` only decides whether we serve them
+    if libpk::config.discord().cache_emojis {
+        resource_types |= ResourceType::EMOJI;
+    }
+    if libpk::config.discord().cache_stickers {
+        resource_types |= ResourceType::STICKER;
+    }
+
     let cache = Arc::new(
         InMemoryCache::builder()
-            .resource_types(
-                ResourceType::GUILD
-                    | ResourceType::CHANNEL
-                    | ResourceType::ROLE
-                    | ResourceType::USER_CURRENT
-                    | ResourceType::MEMBER_CURRENT,
-            )
+            .resource_types(resource_types)
             .message_cache_size(0)
             .build(),
     );
@@ -118,6 +137,8 @@ pub fn new() -> DiscordCache {
         client,
         RwLock::new(Vec::new()),
         RwLock::new(HashMap::new()),
+        RwLock::new(HashSet::new()),
+        member_cache::new(),
     )
 }
 
@@ -126,56 +147,139 @@ pub struct CachedMessage {
     id: Id<MessageMarker>,
     referenced_message: Option<Id<MessageMarker>>,
     author_username: String,
+    author_id: Id<UserMarker>,
+    webhook_id: Option<Id<WebhookMarker>>,
+    // lets consumers check whether a message was edited, without us keeping the content around
+    content_hash: String,
 }
 
+// hex sha256 of the message content, so consumers can compute it themselves
+fn content_hash(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+impl CachedMessage {
+    fn new(m: &Message) -> CachedMessage {
+        CachedMessage {
+            id: m.id,
+            referenced_message: m.referenced_message.as_ref().map(|v| v.id),
+            author_username: m.author.name.clone(),
+            author_id: m.author.id,
+            webhook_id: m.webhook_id,
+            content_hash: content_hash(&m.content),
+        }
+    }
+}
+
+// `current` and `previous` are kept for older consumers, `messages` has the full history
 #[derive(Clone, Serialize)]
 pub struct LastMessageCacheEntry {
     pub current: CachedMessage,
     pub previous: Option<CachedMessage>,
+    /// newest first
+    pub messages: Vec<CachedMessage>,
 }
 
 pub struct DiscordCache(
     pub Arc<InMemoryCache>,
     pub Arc<twilight_http::Client>,
     pub RwLock<Vec<u32>>,
-    pub RwLock<HashMap<Id<ChannelMarker>, LastMessageCacheEntry>>,
+    // most recent messages per channel, newest first
+    pub RwLock<HashMap<Id<ChannelMarker>, VecDeque<CachedMessage>>>,
+    // guilds restored from a snapshot, that we haven't seen a GUILD_CREATE for yet
+    pub RwLock<HashSet<Id<GuildMarker>>>,
+    pub MemberCache,
 );
 
+pub fn shard_for_guild(guild_id: Id<GuildMarker>) -> u32 {
+    ((guild_id.get() >> 22) % cluster_config().total_shards as u64) as u32
+}
+
 impl DiscordCache {
     pub async fn get_last_message(
         &self,
         channel: Id<ChannelMarker>,
     ) -> Option<LastMessageCacheEntry> {
-        self.3.read().await.get(&channel).cloned()
+        let lm = self.3.read().await;
+        let messages = lm.get(&channel)?;
+        Some(LastMessageCacheEntry {
+            current: messages.front()?.clone(),
+            previous: messages.get(1).cloned(),
+            messages: messages.iter().cloned().collect(),
+        })
+    }
+
+    pub async fn stale_guilds(&self, shard_id: u32) -> Vec<Id<GuildMarker>> {
+        self.4
+            .read()
+            .await
+            .iter()
+            .filter(|id| shard_for_guild(**id) == shard_id)
+            .cloned()
+            .collect()
     }
 
-    pub async fn update(&self, event: &twilight_gateway::Event) {
-        self.0.update(event);
+    pub(crate) fn remove_guild(&self, guild_id: Id<GuildMarker>) {
+        match serde_json::from_value::<GuildDelete>(json!({ "id": guild_id, "unavailable": false }))
+        {
+            Ok(delete) => self.0.update(&Event::GuildDelete(delete)),
+            Err(error) => tracing::error!(?error, ?guild_id, "failed to remove guild from cache"),
+        }
+    }
 
+    pub async fn update(&self, shard_id: u32, event: &twilight_gateway::Event) {
         match event {
-            Event::MessageCreate(m) => match self.3.write().await.entry(m.channel_id) {
-                std::collections::hash_map::Entry::Occupied(mut e) => {
-                    let cur = e.get();
-                    e.insert(LastMessageCacheEntry {
-                        current: CachedMessage {
-                            id: m.id,
-                            referenced_message: m.referenced_message.as_ref().map(|v| v.id),
-                            author_username: m.author.name.clone(),
-                        },
-                        previous: Some(cur.current.clone()),
-                    });
+            // twilight drops every guild listed in READY until its GUILD_CREATE arrives,
+            // but we'd rather keep serving restored data for those in the meantime
+            Event::Ready(ready) if !self.stale_guilds(shard_id).await.is_empty() => {
+                self.0
+                    .update(&Event::UserUpdate(UserUpdate(ready.user.clone())));
+
+                // we're not in these guilds anymore
+                let ready_guilds: HashSet<Id<GuildMarker>> =
+                    ready.guilds.iter().map(|g| g.id).collect();
+                let mut stale = self.4.write().await;
+                for guild_id in stale.clone() {
+                    if shard_for_guild(guild_id) == shard_id && !ready_guilds.contains(&guild_id) {
+                        self.remove_guild(guild_id);
+                        stale.remove(&guild_id);
+                    }
                 }
-                std::collections::hash_map::Entry::Vacant(e) => {
-                    e.insert(LastMessageCacheEntry {
-                        current: CachedMessage {
-                            id: m.id,
-                            referenced_message: m.referenced_message.as_ref().map(|v| v.id),
-                            author_username: m.author.name.clone(),
-                        },
-                        previous: None,
-                    });
+            }
+            // resuming replays everything we missed, so restored guilds are up to date
+            Event::Resumed => {
+                self.0.update(event);
+                self.4
+                    .write()
+                    .await
+                    .retain(|id| shard_for_guild(*id) != shard_id);
+            }
+            Event::GuildCreate(guild) => {
+                self.0.update(event);
+                self.4.write().await.remove(&guild.id());
+            }
+            _ => self.0.update(event),
+        }
+
+        self.5.update(event).await;
+
+        match event {
+            Event::MessageCreate(m) => {
+                let size = libpk::config.discord().last_message_cache_size;
+                if size > 0 {
+                    let mut lm = self.3.write().await;
+                    let messages = lm.entry(m.channel_id).or_default();
+                    messages.push_front(CachedMessage::new(m));
+                    messages.truncate(size);
+                }
+            }
+            Event::MessageUpdate(m) => {
+                if let Some(messages) = self.3.write().await.get_mut(&m.channel_id)
+                    && let Some(cached) = messages.iter_mut().find(|v| v.id == m.id)
+                {
+                    *cached = CachedMessage::new(m);
                 }
-            },
+            }
             Event::MessageDelete(m) => {
                 self.handle_message_deletion(m.channel_id, vec![m.id]).await;
             }
@@ -194,51 +298,40 @@ impl DiscordCache {
     ) {
         let mut lm = self.3.write().await;
 
-        let Some(entry) = lm.get(&channel_id) else {
+        let Some(messages) = lm.get_mut(&channel_id) else {
             return;
         };
 
-        let mut entry = entry.clone();
+        messages.retain(|v| !mids.contains(&v.id));
 
-        // if none of the deleted messages are relevant, just return
-        if !mids.contains(&entry.current.id)
-            && entry
-                .previous
-                .clone()
-                .map(|v| !mids.contains(&v.id))
-                .unwrap_or(false)
-        {
-            return;
+        if messages.is_empty() {
+            lm.remove(&channel_id);
         }
+    }
 
-        // remove "previous" entry if it was deleted
-        if let Some(prev) = entry.previous.clone()
-            && mids.contains(&prev.id)
-        {
-            entry.previous = None;
+    // anyone but us, since we don't cache members from the gateway
+    // repeated lookups (like the cache api's per-user permissions) are served from the member cache
+    async fn fetch_member(
+        &self,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+    ) -> anyhow::Result<CachedMember> {
+        if let Some(member) = self.5.get(guild_id, user_id).await {
+            return Ok(member);
         }
 
-        // set "current" entry to "previous" if current entry was deleted
-        // (if the "previous" entry still exists, it was not deleted)
-        if let Some(prev) = entry.previous.clone()
-            && mids.contains(&entry.current.id)
-        {
-            entry.current = prev;
-            entry.previous = None;
-        }
+        let member = member_to_cached_member(
+            self.1
+                .guild_member(guild_id, user_id)
+                .await?
+                .model()
+                .await?,
+            user_id,
+        );
 
-        // if the current entry was already deleted, but previous wasn't,
-        // we would've set current to previous
-        // so if current is deleted this means both current and previous have
-        // been deleted
-        // so just drop the cache entry here
-        if mids.contains(&entry.current.id) && entry.previous.is_none() {
-            lm.remove(&channel_id);
-            return;
-        }
+        self.5.insert(guild_id, member.clone()).await;
 
-        // ok, update the entry
-        lm.insert(channel_id, entry.clone());
+        Ok(member)
     }
 
     pub async fn guild_permissions(
@@ -263,14 +356,7 @@ impl DiscordCache {
                 .value()
                 .to_owned()
         } else {
-            member_to_cached_member(
-                self.1
-                    .guild_member(guild_id, user_id)
-                    .await?
-                    .model()
-                    .await?,
-                user_id,
-            )
+            self.fetch_member(guild_id, user_id).await?
         };
 
         let MemberRoles { assigned, everyone } = self
@@ -335,14 +421,7 @@ impl DiscordCache {
                 .value()
                 .to_owned()
         } else {
-            member_to_cached_member(
-                self.1
-                    .guild_member(guild_id, user_id)
-                    .await?
-                    .model()
-                    .await?,
-                user_id,
-            )
+            self.fetch_member(guild_id, user_id).await?
         };
 
         let MemberRoles { assigned, everyone } = self
@@ -373,6 +452,78 @@ impl DiscordCache {
             .disable_member_communication(&member, permissions))
     }
 
+    /// active threads in a guild, the parent channel is in `parent_id`
+    ///
+    /// None if the guild isn't cached, or `cache_threads` is off
+    pub fn threads(&self, id: Id<GuildMarker>) -> Option<Vec<Channel>> {
+        if !libpk::config.discord().cache_threads {
+            return None;
+        }
+
+        Some(
+            self.0
+                .guild_channels(id)?
+                .iter()
+                .filter_map(|channel_id| {
+                    let channel = self.0.channel(*channel_id)?;
+                    channel.kind.is_thread().then(|| channel.value().clone())
+                })
+                .collect(),
+        )
+    }
+
+    /// None if the guild isn't cached, or `cache_emojis` is off
+    pub fn emojis(&self, id: Id<GuildMarker>) -> Option<Vec<Emoji>> {
+        Some(
+            self.0
+                .guild_emojis(id)?
+                .iter()
+                .filter_map(|emoji_id| {
+                    let emoji = self.0.emoji(*emoji_id)?;
+                    let emoji = emoji.value().resource();
+                    Some(Emoji {
+                        animated: emoji.animated(),
+                        available: emoji.available(),
+                        id: emoji.id(),
+                        managed: emoji.managed(),
+                        name: emoji.name().to_string(),
+                        require_colons: emoji.require_colons(),
+                        roles: emoji.roles().to_vec(),
+                        // we only have the id
+                        user: None,
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    /// None if the guild isn't cached, or `cache_stickers` is off
+    pub fn stickers(&self, id: Id<GuildMarker>) -> Option<Vec<Sticker>> {
+        Some(
+            self.0
+                .guild_stickers(id)?
+                .iter()
+                .filter_map(|sticker_id| {
+                    let sticker = self.0.sticker(*sticker_id)?;
+                    let sticker = sticker.value().resource();
+                    Some(Sticker {
+                        available: sticker.available(),
+                        description: Some(sticker.description().to_string()),
+                        format_type: sticker.format_type(),
+                        guild_id: sticker.guild_id(),
+                        id: sticker.id(),
+                        kind: sticker.kind(),
+                        name: sticker.name().to_string(),
+                        pack_id: sticker.pack_id(),
+                        sort_value: sticker.sort_value(),
+                        tags: sticker.tags().to_string(),
+                        user: None,
+                    })
+                })
+                .collect(),
+        )
+    }
+
     // from https://github.com/Gelbpunkt/gateway-proxy/blob/5bcb080a1fcb09f6fafecad7736819663a625d84/src/cache.rs
     pub fn guild(&self, id: Id<GuildMarker>) -> Option<Guild> {
         self.0.guild(id).map(|guild| {
@@ -395,6 +546,10 @@ impl DiscordCache {
                 })
                 .unwrap_or_default();
 
+            let threads = self.threads(id).unwrap_or_default();
+            let emojis = self.emojis(id).unwrap_or_default();
+            let stickers = self.stickers(id).unwrap_or_default();
+
             let roles = self
                 .0
                 .guild_roles(id)
@@ -419,7 +574,7 @@ impl DiscordCache {
                 default_message_notifications: guild.default_message_notifications(),
                 description: guild.description().map(ToString::to_string),
                 discovery_splash: guild.discovery_splash().map(ToOwned::to_owned),
-                emojis: vec![],
+                emojis,
                 explicit_content_filter: guild.explicit_content_filter(),
                 features: guild.features().cloned().collect(),
                 icon: guild.icon().map(ToOwned::to_owned),
@@ -448,10 +603,10 @@ impl DiscordCache {
                 safety_alerts_channel_id: guild.safety_alerts_channel_id(),
                 splash: guild.splash().map(ToOwned::to_owned),
                 stage_instances: vec![],
-                stickers: vec![],
+                stickers,
                 system_channel_flags: guild.system_channel_flags(),
                 system_channel_id: guild.system_channel_id(),
-                threads: vec![],
+                threads,
                 unavailable: Some(false),
                 vanity_url_code: guild.vanity_url_code().map(ToString::to_string),
                 verification_level: guild.verification_level(),
@@ -2,11 +2,20 @@ use anyhow::anyhow;
 use futures::StreamExt;
 use libpk::{_config::ClusterSettings, runtime_config::RuntimeConfig, state::ShardStateEvent};
 use metrics::counter;
-use std::sync::Arc;
-use tokio::sync::mpsc::Sender;
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Instant,
+};
+use tokio::sync::mpsc::{Sender, error::TrySendError};
 use tracing::{error, info, warn};
 use twilight_gateway::{
-    ConfigBuilder, Event, EventTypeFlags, Message, Shard, ShardId, create_iterator,
+    Config, ConfigBuilder, Event, EventTypeFlags, Message, Shard, ShardId, create_iterator,
+    queue::Queue,
 };
 use twilight_model::gateway::{
     Intents,
@@ -17,35 +26,48 @@ use twilight_model::gateway::{
 use crate::{
     RUNTIME_CONFIG_KEY_EVENT_TARGET,
     discord::identify_queue::{self, RedisQueue},
+    event_metrics::GuildEventTracker,
+    event_targets::EventTargets,
+    replay::Recorder,
+};
+
+use super::{
+    cache::DiscordCache,
+    sessions::{SavedSession, SessionStore},
+    supervisor::ShardSet,
 };
 
-use super::cache::DiscordCache;
+pub const RUNTIME_CONFIG_KEY_TOTAL_SHARDS: &'static str = "total_shards";
 
-pub fn cluster_config() -> ClusterSettings {
-    libpk::config
-        .discord()
-        .cluster
-        .clone()
-        .unwrap_or(libpk::_config::ClusterSettings {
-            node_id: 0,
-            total_shards: 1,
-            total_nodes: 1,
-        })
+// set when we've been resharded to something other than what's in the config
+static TOTAL_SHARDS_OVERRIDE: AtomicU32 = AtomicU32::new(0);
+
+pub fn set_total_shards(total_shards: u32) {
+    TOTAL_SHARDS_OVERRIDE.store(total_shards, Ordering::Relaxed);
 }
 
-pub fn create_shards(redis: fred::clients::RedisPool) -> anyhow::Result<Vec<Shard<RedisQueue>>> {
-    let intents = Intents::GUILDS
-        | Intents::DIRECT_MESSAGES
-        | Intents::DIRECT_MESSAGE_REACTIONS
-        | Intents::GUILD_MESSAGES
-        | Intents::GUILD_MESSAGE_REACTIONS
-        | Intents::MESSAGE_CONTENT;
+pub fn cluster_config() -> ClusterSettings {
+    let mut settings =
+        libpk::config
+            .discord()
+            .cluster
+            .clone()
+            .unwrap_or(libpk::_config::ClusterSettings {
+                node_id: 0,
+                total_shards: 1,
+                total_nodes: 1,
+            });
 
-    let queue = identify_queue::new(redis);
+    match TOTAL_SHARDS_OVERRIDE.load(Ordering::Relaxed) {
+        0 => {}
+        total_shards => settings.total_shards = total_shards,
+    }
 
-    let cluster_settings = cluster_config();
+    settings
+}
 
-    let (start_shard, end_shard): (u32, u32) = if cluster_settings.total_shards < 16 {
+fn shard_range(cluster_settings: &ClusterSettings) -> anyhow::Result<Range<u32>> {
+    if cluster_settings.total_shards < 16 {
         warn!("we have less than 16 shards, assuming single gateway process");
         if cluster_settings.node_id != 0 {
             return Err(anyhow!(
@@ -53,13 +75,33 @@ pub fn create_shards(redis: fred::clients::RedisPool) -> anyhow::Result<Vec<Shar
                 cluster_settings.node_id
             ));
         }
-        (0, (cluster_settings.total_shards - 1).into())
+        Ok(0..cluster_settings.total_shards)
     } else {
-        (
-            (cluster_settings.node_id * 16).into(),
-            (((cluster_settings.node_id + 1) * 16) - 1).into(),
-        )
-    };
+        let per_node =
+            shards_per_node(cluster_settings.total_shards, cluster_settings.total_nodes)?;
+        Ok(cluster_settings.node_id * per_node..(cluster_settings.node_id + 1) * per_node)
+    }
+}
+
+// every node runs an equal, contiguous range of shards
+fn shards_per_node(total_shards: u32, total_nodes: u32) -> anyhow::Result<u32> {
+    if total_nodes == 0 || total_shards % total_nodes != 0 {
+        return Err(anyhow!(
+            "{total_shards} shards can't be split evenly over {total_nodes} nodes"
+        ));
+    }
+    Ok(total_shards / total_nodes)
+}
+
+fn shard_config(redis: fred::clients::RedisPool) -> Config {
+    let intents = Intents::GUILDS
+        | Intents::DIRECT_MESSAGES
+        | Intents::DIRECT_MESSAGE_REACTIONS
+        | Intents::GUILD_MESSAGES
+        | Intents::GUILD_MESSAGE_REACTIONS
+        | Intents::MESSAGE_CONTENT;
+
+    let queue = identify_queue::new(redis);
 
     let prefix = libpk::config.discord().bot_prefix_for_gateway.clone();
 
@@ -71,45 +113,170 @@ pub fn create_shards(redis: fred::clients::RedisPool) -> anyhow::Result<Vec<Shar
         config = config.proxy_url(proxy.clone());
     };
 
-    let shards = create_iterator(
-        start_shard..end_shard + 1,
+    config.build()
+}
+
+fn build_shards(
+    shard_ids: Range<u32>,
+    total_shards: u32,
+    config: Config,
+    saved_sessions: HashMap<u32, SavedSession>,
+) -> Vec<Shard<RedisQueue>> {
+    create_iterator(
+        shard_ids,
+        total_shards,
+        config,
+        |shard_id, builder| match saved_sessions.get(&shard_id.number()) {
+            Some(saved) => {
+                let mut builder = builder.session(saved.session.clone());
+                if let Some(resume_url) = saved.resume_url.clone() {
+                    builder = builder.resume_url(resume_url);
+                }
+                builder.build()
+            }
+            None => builder.build(),
+        },
+    )
+    .collect()
+}
+
+pub async fn create_shards(
+    redis: fred::clients::RedisPool,
+    sessions: &SessionStore,
+) -> anyhow::Result<Vec<Shard<RedisQueue>>> {
+    let cluster_settings = cluster_config();
+    let shard_ids = shard_range(&cluster_settings)?;
+
+    // resume sessions from before a restart, so we don't need to wait for identify
+    let saved_sessions = sessions
+        .load(cluster_settings.total_shards, shard_ids.clone())
+        .await;
+
+    Ok(build_shards(
+        shard_ids,
         cluster_settings.total_shards,
-        config.build(),
-        |_, builder| builder.build(),
-    );
+        shard_config(redis),
+        saved_sessions,
+    ))
+}
 
-    let mut shards_vec = Vec::new();
-    shards_vec.extend(shards);
+/// whether this cluster can move to a different shard count
+///
+/// the shards are split evenly over the nodes, and each node's range has to be whole identify
+/// buckets, so a bucket's shards never identify from two nodes at once
+/// less than 16 shards only works on a single node (see `shard_range`)
+pub fn check_reshard(total_shards: u32) -> anyhow::Result<()> {
+    check_shard_count(
+        total_shards,
+        cluster_config().total_nodes,
+        libpk::config.discord().max_concurrency,
+    )
+}
 
-    Ok(shards_vec)
+fn check_shard_count(
+    total_shards: u32,
+    total_nodes: u32,
+    max_concurrency: u32,
+) -> anyhow::Result<()> {
+    if total_shards == 0 {
+        return Err(anyhow!("can't run without any shards"));
+    }
+    if total_shards < 16 {
+        if total_nodes != 1 {
+            return Err(anyhow!(
+                "can't run less than 16 shards with {total_nodes} nodes"
+            ));
+        }
+        return Ok(());
+    }
+
+    let per_node = shards_per_node(total_shards, total_nodes)?;
+    if per_node % max_concurrency != 0 {
+        return Err(anyhow!(
+            "each node would run {per_node} shards, which isn't a multiple of max_concurrency ({max_concurrency})"
+        ));
+    }
+    Ok(())
+}
+
+/// create this node's shards for a different shard count
+/// these always identify, sessions don't carry over between shard counts
+pub fn create_reshard_set(
+    redis: fred::clients::RedisPool,
+    total_shards: u32,
+) -> anyhow::Result<Vec<Shard<RedisQueue>>> {
+    check_reshard(total_shards)?;
+
+    let cluster_settings = ClusterSettings {
+        total_shards,
+        ..cluster_config()
+    };
+
+    Ok(build_shards(
+        shard_range(&cluster_settings)?,
+        total_shards,
+        shard_config(redis),
+        HashMap::new(),
+    ))
+}
+
+/// create a single shard, to replace one that's gone bad
+/// this resumes the shard's current session if we have one
+pub async fn create_shard(
+    redis: fred::clients::RedisPool,
+    sessions: &SessionStore,
+    shard_id: u32,
+) -> anyhow::Result<Shard<RedisQueue>> {
+    let total_shards = cluster_config().total_shards;
+    let saved_sessions = sessions
+        .get(total_shards, shard_id)
+        .await
+        .map(|saved| HashMap::from([(shard_id, saved)]))
+        .unwrap_or_default();
+
+    build_shards(
+        shard_id..shard_id + 1,
+        total_shards,
+        shard_config(redis),
+        saved_sessions,
+    )
+    .pop()
+    .ok_or_else(|| anyhow!("failed to create shard {shard_id}"))
 }
 
 #[tracing::instrument(fields(shard = %shard.id()), skip_all)]
-pub async fn runner(
-    mut shard: Shard<RedisQueue>,
-    tx: Sender<(ShardId, Event, String)>,
+pub async fn runner<Q: Queue + Unpin>(
+    mut shard: Shard<Q>,
+    tx: Sender<(ShardId, Event, String, bool, Vec<(String, String)>, Instant)>,
     tx_state: Sender<(ShardId, ShardStateEvent, Option<Event>, Option<i32>)>,
     cache: Arc<DiscordCache>,
     runtime_config: Arc<RuntimeConfig>,
+    sessions: Arc<SessionStore>,
+    event_targets: Arc<EventTargets>,
+    shard_set: Arc<ShardSet>,
+    recorder: Option<Arc<Recorder>>,
+    guild_tracker: Arc<GuildEventTracker>,
 ) {
     // let _span = info_span!("shard_runner", shard_id = shard.id().number()).entered();
     let shard_id = shard.id().number();
 
-    let our_user_id = libpk::config.discord().client_id;
+    // kept up to date so the session can be saved on shutdown
+    let session = sessions.handle(shard.id().total(), shard_id).await;
 
     info!("waiting for events");
     while let Some(item) = shard.next().await {
+        let received_at = Instant::now();
         let raw_event = match item {
             Ok(evt) => match evt {
                 Message::Close(frame) => {
-                    let mut state_event = ShardStateEvent::Closed;
+                    let mut state_event = ShardStateEvent::Closed(None);
                     let close_code = if let Some(close) = frame {
-                        match close.code {
+                        state_event = match close.code {
                             4000..=4003 | 4005..=4009 => {
-                                state_event = ShardStateEvent::Reconnect;
+                                ShardStateEvent::Reconnect(Some(close.code))
                             }
-                            _ => {}
-                        }
+                            _ => ShardStateEvent::Closed(Some(close.code)),
+                        };
                         close.code.to_string()
                     } else {
                         "unknown".to_string()
@@ -124,7 +291,9 @@ pub async fn runner(
                     )
                     .increment(1);
 
-                    if let Err(error) = tx_state.try_send((shard.id(), state_event, None, None)) {
+                    if shard_set.is_active()
+                        && let Err(error) = tx_state.try_send((shard.id(), state_event, None, None))
+                    {
                         error!("failed to update shard state for socket closure: {error}");
                     }
 
@@ -138,6 +307,10 @@ pub async fn runner(
             }
         };
 
+        if let Some(recorder) = recorder.as_ref() {
+            recorder.record(shard_id, &raw_event);
+        }
+
         let event = match twilight_gateway::parse(raw_event.clone(), EventTypeFlags::all()) {
             Ok(Some(parsed)) => Event::from(parsed),
             Ok(None) => {
@@ -151,6 +324,27 @@ pub async fn runner(
             }
         };
 
+        match event {
+            Event::Ready(ref ready) => {
+                session.set(shard.session(), Some(ready.resume_gateway_url.clone()))
+            }
+            Event::Resumed => session.set(shard.session(), None),
+            Event::GatewayInvalidateSession(false) => session.set(None, None),
+            _ => {
+                if let Some(current) = shard.session() {
+                    session.set_sequence(current.sequence());
+                }
+            }
+        }
+
+        // shards waiting to take over after resharding don't do anything until then
+        if let Event::Ready(_) | Event::Resumed = event {
+            shard_set.mark_ready(shard_id).await;
+        }
+        if !shard_set.is_active() {
+            continue;
+        }
+
         // log the event in metrics
         // event_type * shard_id is too many labels and prometheus fails to query it
         // so we split it into two metrics
@@ -164,6 +358,7 @@ pub async fn runner(
             "shard_id" => shard_id.to_string(),
         )
         .increment(1);
+        guild_tracker.record(event.guild_id()).await;
 
         // check for shard status events
         match event {
@@ -180,7 +375,7 @@ pub async fn runner(
             Event::GatewayReconnect => {
                 if let Err(error) = tx_state.try_send((
                     shard.id(),
-                    ShardStateEvent::Reconnect,
+                    ShardStateEvent::Reconnect(None),
                     Some(event.clone()),
                     None,
                 )) {
@@ -206,35 +401,70 @@ pub async fn runner(
             _ => {}
         }
 
-        if let Event::Ready(_) = event {
+        // resumed sessions (see sessions.rs) never get a READY
+        if let Event::Ready(_) | Event::Resumed = event {
             if !cache.2.read().await.contains(&shard_id) {
                 cache.2.write().await.push(shard_id);
             }
         }
-        cache.update(&event).await;
+        cache.update(shard_id, &event).await;
 
         // okay, we've handled the event internally, let's send it to consumers
+        let (for_bot, extra_targets) = consumers(&event, &runtime_config, &event_targets).await;
 
-        // some basic filtering here is useful
-        // we can't use if matching using the | operator, so anything matched does nothing
-        // and the default match skips the next block (continues to the next event)
-        match event {
-            Event::InteractionCreate(_) => {}
-            Event::MessageCreate(ref m) if m.author.id != our_user_id => {}
-            Event::MessageUpdate(ref m) if m.author.id != our_user_id && !m.author.bot => {}
-            Event::MessageDelete(_) => {}
-            Event::MessageDeleteBulk(_) => {}
-            Event::ReactionAdd(ref r) if r.user_id != our_user_id => {}
-            _ => {
-                continue;
+        // never wait here, or we'd stop heartbeating
+        // the forwarder spills to the spool (or gives up on busy targets) instead of blocking,
+        // so this only fills up if the forwarder itself is stuck
+        if for_bot || !extra_targets.is_empty() {
+            match tx.try_send((
+                shard.id(),
+                event,
+                raw_event,
+                for_bot,
+                extra_targets,
+                received_at,
+            )) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!(?shard_id, "event queue is full, dropping event");
+                    counter!("pluralkit_gateway_events_dropped", "reason" => "queue_full")
+                        .increment(1);
+                }
+                Err(TrySendError::Closed(_)) => {
+                    tracing::error!("error sending shard event: channel closed");
+                    counter!("pluralkit_gateway_events_dropped", "reason" => "channel_closed")
+                        .increment(1);
+                }
             }
         }
+    }
+}
 
-        if runtime_config.exists(RUNTIME_CONFIG_KEY_EVENT_TARGET).await {
-            if let Err(error) = tx.try_send((shard.id(), event, raw_event)) {
-                tracing::error!(?error, "error sending shard event");
-            }
-        }
+/// whether the bot wants this event, and which extra targets want it
+pub async fn consumers(
+    event: &Event,
+    runtime_config: &RuntimeConfig,
+    event_targets: &EventTargets,
+) -> (bool, Vec<(String, String)>) {
+    let for_bot =
+        wanted_by_bot(event) && runtime_config.exists(RUNTIME_CONFIG_KEY_EVENT_TARGET).await;
+
+    (for_bot, event_targets.matching(event).await)
+}
+
+// some basic filtering here is useful
+// the bot only cares about a few event types, other targets bring their own filters
+pub fn wanted_by_bot(event: &Event) -> bool {
+    let our_user_id = libpk::config.discord().client_id;
+
+    match event {
+        Event::InteractionCreate(_) => true,
+        Event::MessageCreate(m) if m.author.id != our_user_id => true,
+        Event::MessageUpdate(m) if m.author.id != our_user_id && !m.author.bot => true,
+        Event::MessageDelete(_) => true,
+        Event::MessageDeleteBulk(_) => true,
+        Event::ReactionAdd(r) if r.user_id != our_user_id => true,
+        _ => false,
     }
 }
 
@@ -267,3 +497,170 @@ pub fn presence(status: &str, going_away: bool) -> UpdatePresencePayload {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        discord::{cache, sessions, supervisor::shard_set},
+        event_metrics::guild_tracker,
+        event_targets,
+        test_util::{fake_gateway, message_create},
+    };
+    use serde_json::{Value, json};
+    use std::time::Duration;
+    use tokio::sync::mpsc::{Receiver, channel};
+    use twilight_gateway::EventType;
+    use twilight_model::id::Id;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    struct Harness {
+        url: String,
+        rx: Receiver<(ShardId, Event, String, bool, Vec<(String, String)>, Instant)>,
+        rx_state: Receiver<(ShardId, ShardStateEvent, Option<Event>, Option<i32>)>,
+        cache: Arc<DiscordCache>,
+    }
+
+    // a shard runner connected to a fake gateway playing this scenario
+    async fn run_scenario(scenario: Value) -> Harness {
+        crate::test_util::init_config();
+
+        let url = fake_gateway(scenario).await;
+        let runtime_config = crate::test_util::runtime_config();
+        let cache = Arc::new(cache::new());
+        let (tx, rx) = channel(100);
+        let (tx_state, rx_state) = channel(100);
+
+        let config = ConfigBuilder::new("token".to_string(), Intents::GUILD_MESSAGES)
+            .proxy_url(url.clone())
+            .build();
+        tokio::spawn(runner(
+            Shard::with_config(ShardId::ONE, config),
+            tx,
+            tx_state,
+            cache.clone(),
+            runtime_config.clone(),
+            Arc::new(sessions::new(crate::test_util::redis())),
+            Arc::new(event_targets::new(runtime_config)),
+            Arc::new(shard_set(true)),
+            None,
+            Arc::new(guild_tracker(0)),
+        ));
+
+        Harness {
+            url,
+            rx,
+            rx_state,
+            cache,
+        }
+    }
+
+    impl Harness {
+        // the kinds of the shard state updates sent up to the first one for `kind`
+        async fn wait_for_state(&mut self, kind: EventType) -> Vec<EventType> {
+            let mut seen = Vec::new();
+            tokio::time::timeout(TIMEOUT, async {
+                while let Some((_, _, event, _)) = self.rx_state.recv().await {
+                    if let Some(event) = event {
+                        seen.push(event.kind());
+                        if event.kind() == kind {
+                            return;
+                        }
+                    }
+                }
+                panic!("shard runner stopped");
+            })
+            .await
+            .unwrap_or_else(|_| panic!("timed out waiting for {kind:?}, got {seen:?}"));
+            seen
+        }
+
+        async fn identify_count(&self) -> usize {
+            let url = format!("{}/identifies", self.url.replacen("ws://", "http://", 1));
+            let body = reqwest::get(url).await.unwrap().text().await.unwrap();
+            serde_json::from_str::<Vec<Value>>(&body).unwrap().len()
+        }
+    }
+
+    #[tokio::test]
+    async fn runner_forwards_events_from_fake_gateway() {
+        let mut harness = run_scenario(json!({ "steps": [message_create(1)] })).await;
+
+        let (_, event, _, for_bot, extra_targets, _) =
+            tokio::time::timeout(TIMEOUT, harness.rx.recv())
+                .await
+                .expect("timed out waiting for events")
+                .expect("shard runner stopped");
+
+        assert_eq!(event.kind(), EventType::MessageCreate);
+        assert!(for_bot);
+        assert!(extra_targets.is_empty());
+        // the shard has to identify before discord sends it anything else
+        harness.wait_for_state(EventType::Ready).await;
+        assert!(harness.cache.get_last_message(Id::new(10)).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn runner_resumes_after_reconnect() {
+        let mut harness = run_scenario(json!({
+            "steps": [{ "action": "reconnect" }, message_create(1)],
+        }))
+        .await;
+
+        let seen = harness.wait_for_state(EventType::Resumed).await;
+        assert!(seen.contains(&EventType::GatewayReconnect));
+
+        // steps carry on after the resume, so the message still arrives
+        let (_, event, ..) = tokio::time::timeout(TIMEOUT, harness.rx.recv())
+            .await
+            .expect("timed out waiting for events")
+            .expect("shard runner stopped");
+        assert_eq!(event.kind(), EventType::MessageCreate);
+        assert_eq!(harness.identify_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn runner_resumes_zombied_connection() {
+        let mut harness = run_scenario(json!({
+            "heartbeat_interval": 200,
+            "steps": [{ "action": "stop_acks" }],
+        }))
+        .await;
+
+        // the shard notices its heartbeats aren't acked, and reconnects on its own
+        harness.wait_for_state(EventType::Resumed).await;
+        assert_eq!(harness.identify_count().await, 1);
+    }
+
+    #[test]
+    fn shard_counts_split_evenly_over_nodes() {
+        assert!(check_shard_count(64, 4, 16).is_ok());
+        // growing a cluster without adding nodes
+        assert!(check_shard_count(128, 4, 16).is_ok());
+        assert!(check_shard_count(50, 4, 1).is_err());
+        assert!(check_shard_count(96, 4, 16).is_err());
+        assert!(check_shard_count(0, 1, 1).is_err());
+    }
+
+    #[test]
+    fn small_shard_counts_need_a_single_node() {
+        assert!(check_shard_count(4, 1, 16).is_ok());
+        assert!(check_shard_count(4, 2, 1).is_err());
+    }
+
+    #[test]
+    fn shard_ranges_follow_the_shard_count() {
+        let range = |node_id, total_shards| {
+            shard_range(&ClusterSettings {
+                node_id,
+                total_shards,
+                total_nodes: 4,
+            })
+            .unwrap()
+        };
+        assert_eq!(range(1, 64), 16..32);
+        assert_eq!(range(1, 128), 32..64);
+        assert_eq!(range(3, 128), 96..128);
+    }
+}
@@ -0,0 +1,218 @@
+use metrics::{counter, gauge};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+use twilight_cache_inmemory::model::CachedMember;
+use twilight_gateway::Event;
+use twilight_model::{
+    guild::PartialMember,
+    id::{
+        Id,
+        marker::{GuildMarker, UserMarker},
+    },
+};
+
+// when the cache is full, drop this fraction of the oldest entries at once,
+// so we don't have to scan the whole map on every insert
+const EVICT_FRACTION: usize = 10;
+
+/// members of recently active users, so permission checks don't need to fetch them over REST
+///
+/// we don't have the GUILD_MEMBERS intent, so we almost never get member updates -
+/// a member's roles being changed won't show up here until their entry expires
+/// (or they send another message), so `member_cache_ttl` is how stale permissions can be
+/// changes to the roles themselves are fine, those come from the main cache
+///
+/// off if `member_cache_size` is 0
+pub struct MemberCache {
+    members: RwLock<HashMap<(Id<GuildMarker>, Id<UserMarker>), (Instant, CachedMember)>>,
+    max_size: usize,
+    ttl: Duration,
+}
+
+pub fn new() -> MemberCache {
+    with_limits(
+        libpk::config.discord().member_cache_size,
+        Duration::from_secs(libpk::config.discord().member_cache_ttl),
+    )
+}
+
+fn with_limits(max_size: usize, ttl: Duration) -> MemberCache {
+    MemberCache {
+        members: RwLock::new(HashMap::new()),
+        max_size,
+        ttl,
+    }
+}
+
+fn partial_member_to_cached_member(member: &PartialMember, id: Id<UserMarker>) -> CachedMember {
+    CachedMember {
+        avatar: member.avatar,
+        communication_disabled_until: member.communication_disabled_until,
+        deaf: Some(member.deaf),
+        flags: member.flags,
+        joined_at: member.joined_at,
+        mute: Some(member.mute),
+        nick: member.nick.clone(),
+        premium_since: member.premium_since,
+        roles: member.roles.clone(),
+        pending: false,
+        user_id: id,
+    }
+}
+
+impl MemberCache {
+    pub async fn get(
+        &self,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+    ) -> Option<CachedMember> {
+        let res = match self.members.read().await.get(&(guild_id, user_id)) {
+            Some((cached_at, member)) if cached_at.elapsed() < self.ttl => Some(member.clone()),
+            _ => None,
+        };
+
+        counter!(
+            "pluralkit_gateway_member_cache",
+            "result" => if res.is_some() { "hit" } else { "miss" }
+        )
+        .increment(1);
+
+        res
+    }
+
+    pub async fn insert(&self, guild_id: Id<GuildMarker>, member: CachedMember) {
+        if self.max_size == 0 {
+            return;
+        }
+
+        let mut members = self.members.write().await;
+
+        if members.len() >= self.max_size && !members.contains_key(&(guild_id, member.user_id)) {
+            members.retain(|_, (cached_at, _)| cached_at.elapsed() < self.ttl);
+
+            if members.len() >= self.max_size {
+                let mut ages: Vec<Instant> = members.values().map(|(at, _)| *at).collect();
+                let idx = (ages.len() / EVICT_FRACTION).max(1) - 1;
+                let (_, cutoff, _) = ages.select_nth_unstable(idx);
+                let cutoff = *cutoff;
+                members.retain(|_, (cached_at, _)| *cached_at > cutoff);
+            }
+        }
+
+        members.insert((guild_id, member.user_id), (Instant::now(), member));
+        gauge!("pluralkit_gateway_member_cache_size").set(members.len() as f64);
+    }
+
+    pub async fn update(&self, event: &Event) {
+        match event {
+            Event::MessageCreate(m) => {
+                if let Some(guild_id) = m.guild_id
+                    && let Some(member) = m.member.as_ref()
+                {
+                    self.insert(
+                        guild_id,
+                        partial_member_to_cached_member(member, m.author.id),
+                    )
+                    .await;
+                }
+            }
+            // only touch members we already have, we'd be missing fields otherwise
+            Event::MemberUpdate(m) => {
+                let mut members = self.members.write().await;
+                if let Some((cached_at, member)) = members.get_mut(&(m.guild_id, m.user.id)) {
+                    member.avatar = m.avatar;
+                    member.communication_disabled_until = m.communication_disabled_until;
+                    member.nick = m.nick.clone();
+                    member.premium_since = m.premium_since;
+                    member.roles = m.roles.clone();
+                    member.pending = m.pending;
+                    *cached_at = Instant::now();
+                }
+            }
+            // a deleted role would make the permission calculation fail
+            Event::RoleDelete(r) => {
+                for ((guild_id, _), (_, member)) in self.members.write().await.iter_mut() {
+                    if *guild_id == r.guild_id {
+                        member.roles.retain(|role_id| *role_id != r.role_id);
+                    }
+                }
+            }
+            Event::MemberRemove(m) => {
+                self.members.write().await.remove(&(m.guild_id, m.user.id));
+            }
+            Event::GuildDelete(g) => {
+                self.members
+                    .write()
+                    .await
+                    .retain(|(guild_id, _), _| *guild_id != g.id);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use twilight_model::guild::MemberFlags;
+
+    fn member(user_id: u64) -> CachedMember {
+        CachedMember {
+            avatar: None,
+            communication_disabled_until: None,
+            deaf: None,
+            flags: MemberFlags::empty(),
+            joined_at: None,
+            mute: None,
+            nick: None,
+            premium_since: None,
+            roles: Vec::new(),
+            pending: false,
+            user_id: Id::new(user_id),
+        }
+    }
+
+    const GUILD: Id<GuildMarker> = Id::new(1);
+
+    #[tokio::test]
+    async fn disabled_when_size_is_zero() {
+        let cache = with_limits(0, Duration::from_secs(60));
+        cache.insert(GUILD, member(1)).await;
+        assert!(cache.get(GUILD, Id::new(1)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn evicts_oldest_when_full() {
+        let cache = with_limits(EVICT_FRACTION, Duration::from_secs(60));
+        for user_id in 1..=EVICT_FRACTION as u64 {
+            cache.insert(GUILD, member(user_id)).await;
+        }
+
+        cache.insert(GUILD, member(100)).await;
+
+        assert!(cache.get(GUILD, Id::new(1)).await.is_none());
+        assert!(cache.get(GUILD, Id::new(100)).await.is_some());
+        assert!(cache.members.read().await.len() <= EVICT_FRACTION);
+    }
+
+    #[tokio::test]
+    async fn reinserting_doesnt_evict() {
+        let cache = with_limits(2, Duration::from_secs(60));
+        cache.insert(GUILD, member(1)).await;
+        cache.insert(GUILD, member(2)).await;
+        cache.insert(GUILD, member(1)).await;
+
+        assert!(cache.get(GUILD, Id::new(1)).await.is_some());
+        assert!(cache.get(GUILD, Id::new(2)).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_misses() {
+        let cache = with_limits(10, Duration::ZERO);
+        cache.insert(GUILD, member(1)).await;
+        assert!(cache.get(GUILD, Id::new(1)).await.is_none());
+    }
+}
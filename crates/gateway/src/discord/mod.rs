@@ -1,4 +1,8 @@
 pub mod cache;
 pub mod gateway;
 pub mod identify_queue;
+pub mod member_cache;
+pub mod sessions;
 pub mod shard_state;
+pub mod snapshot;
+pub mod supervisor;
@@ -0,0 +1,154 @@
+use fred::{clients::RedisPool, interfaces::KeysInterface, types::Expiration};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::sync::RwLock;
+use tracing::{error, info};
+use twilight_gateway::Session;
+
+// if we're down for longer than this, discord probably won't let us resume anyway
+const SESSION_TTL: i64 = 300;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SavedSession {
+    pub session: Session,
+    pub resume_url: Option<String>,
+}
+
+/// a shard's current session, kept up to date by its runner
+///
+/// the sequence changes with every event, so it's kept separately from the rest,
+/// which only changes when the shard identifies
+#[derive(Default)]
+pub struct ShardSession {
+    // session id and resume url
+    id: Mutex<Option<(String, Option<String>)>>,
+    sequence: AtomicU64,
+}
+
+impl ShardSession {
+    pub fn set_sequence(&self, sequence: u64) {
+        self.sequence.store(sequence, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, session: Option<&Session>, resume_url: Option<String>) {
+        let mut id = self.id.lock().expect("session lock poisoned");
+        match session {
+            Some(session) => {
+                // only READY has a resume url, keep the one we had for RESUMED
+                let resume_url = resume_url
+                    .or_else(|| id.as_ref().and_then(|(_, resume_url)| resume_url.clone()));
+                *id = Some((session.id().to_string(), resume_url));
+                self.set_sequence(session.sequence());
+            }
+            None => *id = None,
+        }
+    }
+
+    fn snapshot(&self) -> Option<SavedSession> {
+        let (session_id, resume_url) = self.id.lock().expect("session lock poisoned").clone()?;
+        Some(SavedSession {
+            session: Session::new(self.sequence.load(Ordering::Relaxed), session_id),
+            resume_url,
+        })
+    }
+}
+
+/// sessions are only saved on shutdown, after the shards have stopped reading events
+///
+/// saving them while running would let a crashed gateway resume from an older sequence,
+/// and discord would replay events (like MESSAGE_CREATE) that were already forwarded
+pub struct SessionStore {
+    redis: RedisPool,
+    // by (total shards, shard id), so shards started for a reshard don't mix with the current ones
+    sessions: RwLock<HashMap<(u32, u32), Arc<ShardSession>>>,
+}
+
+pub fn new(redis: RedisPool) -> SessionStore {
+    SessionStore {
+        redis,
+        sessions: RwLock::new(HashMap::new()),
+    }
+}
+
+// sessions can't be resumed with a different shard count
+fn redis_key(total_shards: u32, shard_id: u32) -> String {
+    format!("pluralkit:gateway:session:{total_shards}:{shard_id}")
+}
+
+impl SessionStore {
+    /// takes the sessions saved by the previous run
+    /// they're removed from redis, so they can't be resumed twice
+    pub async fn load(
+        &self,
+        total_shards: u32,
+        shard_ids: impl Iterator<Item = u32>,
+    ) -> HashMap<u32, SavedSession> {
+        let mut res = HashMap::new();
+        for shard_id in shard_ids {
+            match self
+                .redis
+                .getdel::<Option<String>, String>(redis_key(total_shards, shard_id))
+                .await
+            {
+                Ok(Some(value)) => match serde_json::from_str::<SavedSession>(&value) {
+                    Ok(session) => {
+                        res.insert(shard_id, session);
+                    }
+                    Err(error) => error!(?error, shard_id, "failed to parse saved session"),
+                },
+                Ok(None) => {}
+                Err(error) => error!(?error, shard_id, "failed to fetch saved session"),
+            }
+        }
+        info!("loaded {} saved shard sessions", res.len());
+        res
+    }
+
+    /// the session a shard's runner should keep updated
+    pub async fn handle(&self, total_shards: u32, shard_id: u32) -> Arc<ShardSession> {
+        self.sessions
+            .write()
+            .await
+            .entry((total_shards, shard_id))
+            .or_default()
+            .clone()
+    }
+
+    pub async fn get(&self, total_shards: u32, shard_id: u32) -> Option<SavedSession> {
+        self.sessions
+            .read()
+            .await
+            .get(&(total_shards, shard_id))?
+            .snapshot()
+    }
+
+    pub async fn save_all(&self, total_shards: u32) -> anyhow::Result<()> {
+        let sessions: Vec<(u32, SavedSession)> = self
+            .sessions
+            .read()
+            .await
+            .iter()
+            .filter(|((total, _), _)| *total == total_shards)
+            .filter_map(|((_, shard_id), session)| Some((*shard_id, session.snapshot()?)))
+            .collect();
+
+        for (shard_id, session) in sessions {
+            self.redis
+                .set::<(), String, String>(
+                    redis_key(total_shards, shard_id),
+                    serde_json::to_string(&session)?,
+                    Some(Expiration::EX(SESSION_TTL)),
+                    None,
+                    false,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
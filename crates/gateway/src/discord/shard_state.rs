@@ -1,4 +1,7 @@
-use fred::{clients::RedisPool, interfaces::HashesInterface};
+use fred::{
+    clients::RedisPool,
+    interfaces::{HashesInterface, SortedSetsInterface},
+};
 use metrics::{counter, gauge};
 use tokio::sync::RwLock;
 use tracing::info;
@@ -6,19 +9,28 @@ use twilight_gateway::Event;
 
 use std::collections::HashMap;
 
-use libpk::state::ShardState;
+use libpk::state::{
+    SHARD_HISTORY_RETENTION, ShardLatencySample, ShardState, ShardTransition, ShardTransitionKind,
+    missed_down, shard_latency_key, shard_transitions_key,
+};
+
+// heartbeats are every ~41s, we don't need all of them in the history
+const LATENCY_SAMPLE_INTERVAL: i64 = 15 * 60;
 
 use super::gateway::cluster_config;
 
 pub struct ShardStateManager {
     redis: RedisPool,
     shards: RwLock<HashMap<u32, ShardState>>,
+    // when each shard's latency was last added to its history
+    latency_sampled: RwLock<HashMap<u32, i64>>,
 }
 
 pub fn new(redis: RedisPool) -> ShardStateManager {
     ShardStateManager {
         redis: redis,
         shards: RwLock::new(HashMap::new()),
+        latency_sampled: RwLock::new(HashMap::new()),
     }
 }
 
@@ -49,6 +61,72 @@ impl ShardStateManager {
         Ok(())
     }
 
+    // append to one of the history sorted sets, dropping anything past retention
+    async fn add_history(&self, key: String, timestamp: i64, value: String) -> anyhow::Result<()> {
+        self.redis
+            .zadd::<(), _, _>(&key, None, None, false, false, (timestamp as f64, value))
+            .await?;
+        self.redis
+            .zremrangebyscore::<(), _, _, _>(
+                &key,
+                "-inf",
+                (timestamp - SHARD_HISTORY_RETENTION) as f64,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn add_transition(
+        &self,
+        shard_id: u32,
+        kind: ShardTransitionKind,
+        close_code: Option<u16>,
+    ) -> anyhow::Result<()> {
+        let transition = ShardTransition {
+            timestamp: chrono::offset::Utc::now().timestamp(),
+            kind,
+            close_code,
+        };
+        self.add_history(
+            shard_transitions_key(shard_id as i32),
+            transition.timestamp,
+            serde_json::to_string(&transition)?,
+        )
+        .await
+    }
+
+    /// close out shards the previous run left up, since it never got to record them going down
+    pub async fn close_stale(&self, shard_ids: impl Iterator<Item = u32>) -> anyhow::Result<()> {
+        for shard_id in shard_ids {
+            let key = shard_transitions_key(shard_id as i32);
+            let last: Vec<String> = self.redis.zrevrange(&key, 0, 0, false).await?;
+            let Some(last) = last
+                .first()
+                .and_then(|last| serde_json::from_str::<ShardTransition>(last).ok())
+            else {
+                continue;
+            };
+
+            let state: Option<String> = self
+                .redis
+                .hget("pluralkit:shardstatus", shard_id.to_string())
+                .await?;
+            let state = state
+                .and_then(|state| serde_json::from_str::<ShardState>(&state).ok())
+                .unwrap_or_default();
+
+            if let Some(down) = missed_down(&last, &state) {
+                info!(
+                    shard_id,
+                    "shard was left up by the previous run, marking it down"
+                );
+                self.add_history(key, down.timestamp, serde_json::to_string(&down)?)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
     async fn get_shard(&self, id: u32) -> Option<ShardState> {
         let shards = self.shards.read().await;
         shards.get(&id).cloned()
@@ -83,10 +161,25 @@ impl ShardStateManager {
         info.up = true;
 
         self.save_shard(shard_id, info).await?;
+        self.add_transition(
+            shard_id,
+            if resumed {
+                ShardTransitionKind::Resumed
+            } else {
+                ShardTransitionKind::Ready
+            },
+            None,
+        )
+        .await?;
         Ok(())
     }
 
-    pub async fn socket_closed(&self, shard_id: u32, reconnect: bool) -> anyhow::Result<()> {
+    pub async fn socket_closed(
+        &self,
+        shard_id: u32,
+        reconnect: bool,
+        close_code: Option<u16>,
+    ) -> anyhow::Result<()> {
         gauge!("pluralkit_gateway_shard_up").decrement(1);
 
         let mut info = self
@@ -94,6 +187,9 @@ impl ShardStateManager {
             .await
             .unwrap_or(ShardState::default());
 
+        // only the first close after being up is a transition
+        let was_up = info.up;
+
         info.shard_id = shard_id as i32;
         info.cluster_id = Some(cluster_config().node_id as i32);
         info.up = false;
@@ -103,6 +199,10 @@ impl ShardStateManager {
         info.disconnection_count += 1;
 
         self.save_shard(shard_id, info).await?;
+        if was_up {
+            self.add_transition(shard_id, ShardTransitionKind::Down, close_code)
+                .await?;
+        }
         Ok(())
     }
 
@@ -121,6 +221,21 @@ impl ShardStateManager {
         info.latency = latency;
 
         self.save_shard(shard_id, info).await?;
+
+        let now = chrono::offset::Utc::now().timestamp();
+        let last_sampled = self.latency_sampled.read().await.get(&shard_id).copied();
+        if last_sampled.is_none_or(|t| now - t >= LATENCY_SAMPLE_INTERVAL) {
+            self.latency_sampled.write().await.insert(shard_id, now);
+            self.add_history(
+                shard_latency_key(shard_id as i32),
+                now,
+                serde_json::to_string(&ShardLatencySample {
+                    timestamp: now,
+                    latency,
+                })?,
+            )
+            .await?;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{error, info, warn};
+use twilight_gateway::Event;
+use twilight_model::{
+    gateway::payload::incoming::{GuildCreate, UserUpdate},
+    guild::Member,
+    user::CurrentUser,
+};
+
+use super::cache::DiscordCache;
+
+// guild data older than this is too out of date to be useful
+const MAX_SNAPSHOT_AGE: i64 = 30 * 60;
+
+#[derive(Serialize, Deserialize)]
+struct CacheSnapshot {
+    created: i64,
+    current_user: Option<CurrentUser>,
+    // serialized `Guild`s, including our own member
+    guilds: Vec<Value>,
+}
+
+// the cache only keeps our own member, and without the user object
+// rebuild a full `Member` out of the cached member and the current user
+fn current_member(
+    cache: &DiscordCache,
+    current_user: &CurrentUser,
+    guild: &Value,
+) -> Option<Member> {
+    let guild_id = serde_json::from_value(guild["id"].clone()).ok()?;
+    let member = cache.0.member(guild_id, current_user.id)?;
+
+    let mut value = serde_json::to_value(member.value()).ok()?;
+    let obj = value.as_object_mut()?;
+    obj.remove("user_id");
+    obj.insert("user".to_string(), serde_json::to_value(current_user).ok()?);
+    for key in ["deaf", "mute", "pending"] {
+        if obj.get(key).is_none_or(Value::is_null) {
+            obj.insert(key.to_string(), Value::Bool(false));
+        }
+    }
+
+    serde_json::from_value(value).ok()
+}
+
+pub async fn save(cache: &DiscordCache, path: &str) -> anyhow::Result<()> {
+    let current_user = cache.0.current_user();
+
+    let mut guilds = Vec::new();
+    let guild_ids: Vec<_> = cache.0.iter().guilds().map(|g| *g.key()).collect();
+    for guild_id in guild_ids {
+        let Some(guild) = cache.guild(guild_id) else {
+            continue;
+        };
+        let mut guild = serde_json::to_value(guild)?;
+        if let Some(current_user) = current_user.as_ref()
+            && let Some(member) = current_member(cache, current_user, &guild)
+        {
+            guild["members"] = serde_json::to_value(vec![member])?;
+        }
+        guilds.push(guild);
+    }
+
+    let snapshot = CacheSnapshot {
+        created: chrono::Utc::now().timestamp(),
+        current_user,
+        guilds,
+    };
+
+    // write next to the snapshot and move it over, so a crash mid-write can't leave a truncated file
+    let tmp_path = format!("{path}.tmp");
+    tokio::fs::write(&tmp_path, serde_json::to_vec(&snapshot)?).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    info!("saved cache snapshot with {} guilds", snapshot.guilds.len());
+
+    Ok(())
+}
+
+/// restored guilds are marked as stale until we get a GUILD_CREATE (or RESUMED) for them
+pub async fn restore(cache: &DiscordCache, path: &str) -> anyhow::Result<()> {
+    let data = match tokio::fs::read(path).await {
+        Ok(data) => data,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            info!("no cache snapshot found, starting with an empty cache");
+            return Ok(());
+        }
+        Err(error) => return Err(error.into()),
+    };
+
+    // never restore the same snapshot twice
+    tokio::fs::remove_file(path).await?;
+
+    let snapshot: CacheSnapshot = serde_json::from_slice(&data)?;
+
+    let age = chrono::Utc::now().timestamp() - snapshot.created;
+    if age > MAX_SNAPSHOT_AGE {
+        warn!(age, "cache snapshot is too old, not restoring");
+        return Ok(());
+    }
+
+    // needed to cache our own member in each guild
+    if let Some(current_user) = snapshot.current_user {
+        cache.0.update(&Event::UserUpdate(UserUpdate(current_user)));
+    }
+
+    let mut stale = cache.4.write().await;
+    for guild in snapshot.guilds {
+        match serde_json::from_value::<GuildCreate>(guild) {
+            Ok(guild) => {
+                stale.insert(guild.id());
+                cache.0.update(&Event::GuildCreate(Box::new(guild)));
+            }
+            Err(error) => error!(?error, "failed to parse guild from cache snapshot"),
+        }
+    }
+
+    info!(age, "restored {} guilds from cache snapshot", stale.len());
+
+    Ok(())
+}
@@ -0,0 +1,479 @@
+use fred::clients::RedisPool;
+use libpk::{runtime_config::RuntimeConfig, state::ShardStateEvent};
+use metrics::counter;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{Notify, RwLock, mpsc::Sender},
+    task::JoinHandle,
+};
+use tracing::{error, info, warn};
+use twilight_gateway::{Event, MessageSender, Shard, ShardId, queue::Queue};
+
+use crate::{event_metrics::GuildEventTracker, event_targets::EventTargets, replay::Recorder};
+
+use super::{cache::DiscordCache, gateway, sessions::SessionStore, shard_state::ShardStateManager};
+
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(30);
+// discord asks for a heartbeat every ~41s, so this is a few missed ACKs in a row
+const ZOMBIE_THRESHOLD: i64 = 120;
+// give a freshly (re)started shard time to identify before judging it
+const RESTART_GRACE: Duration = Duration::from_secs(180);
+// identifying a whole new shard set goes through the identify queue, so this can take a while
+const RESHARD_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const RESHARD_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// a group of shards started for the same shard count
+///
+/// only the active set handles events; while resharding, the new set connects
+/// in the background and just reports when its shards are ready
+pub struct ShardSet {
+    active: AtomicBool,
+    ready: RwLock<HashSet<u32>>,
+    // woken when a shard becomes ready, so resharding doesn't have to poll for it
+    became_ready: Notify,
+}
+
+pub(super) fn shard_set(active: bool) -> ShardSet {
+    ShardSet {
+        active: AtomicBool::new(active),
+        ready: RwLock::new(HashSet::new()),
+        became_ready: Notify::new(),
+    }
+}
+
+impl ShardSet {
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub async fn mark_ready(&self, shard_id: u32) {
+        self.ready.write().await.insert(shard_id);
+        self.became_ready.notify_one();
+    }
+}
+
+struct RunningShard {
+    id: ShardId,
+    handle: JoinHandle<()>,
+    sender: MessageSender,
+    started: Instant,
+    set: Arc<ShardSet>,
+}
+
+/// owns the shard runner tasks, so a single shard can be restarted
+/// without taking the rest of the gateway down with it
+pub struct ShardSupervisor {
+    redis: RedisPool,
+    tx: Sender<(ShardId, Event, String, bool, Vec<(String, String)>, Instant)>,
+    tx_state: Sender<(ShardId, ShardStateEvent, Option<Event>, Option<i32>)>,
+    cache: Arc<DiscordCache>,
+    runtime_config: Arc<RuntimeConfig>,
+    sessions: Arc<SessionStore>,
+    event_targets: Arc<EventTargets>,
+    recorder: Option<Arc<Recorder>>,
+    guild_tracker: Arc<GuildEventTracker>,
+    shards: RwLock<HashMap<u32, RunningShard>>,
+    // shards that were stopped for a restart, but couldn't be started again
+    // the watchdog keeps trying them
+    failed_restarts: RwLock<HashSet<u32>>,
+    // the active set, for shards that aren't replacing an existing one
+    current_set: RwLock<Arc<ShardSet>>,
+    resharding: AtomicBool,
+}
+
+pub fn new(
+    redis: RedisPool,
+    tx: Sender<(ShardId, Event, String, bool, Vec<(String, String)>, Instant)>,
+    tx_state: Sender<(ShardId, ShardStateEvent, Option<Event>, Option<i32>)>,
+    cache: Arc<DiscordCache>,
+    runtime_config: Arc<RuntimeConfig>,
+    sessions: Arc<SessionStore>,
+    event_targets: Arc<EventTargets>,
+    recorder: Option<Arc<Recorder>>,
+    guild_tracker: Arc<GuildEventTracker>,
+) -> ShardSupervisor {
+    ShardSupervisor {
+        redis,
+        tx,
+        tx_state,
+        cache,
+        runtime_config,
+        sessions,
+        event_targets,
+        recorder,
+        guild_tracker,
+        shards: RwLock::new(HashMap::new()),
+        failed_restarts: RwLock::new(HashSet::new()),
+        current_set: RwLock::new(Arc::new(shard_set(true))),
+        resharding: AtomicBool::new(false),
+    }
+}
+
+impl ShardSupervisor {
+    fn start<Q: Queue + Unpin + Send + Sync + 'static>(
+        &self,
+        shard: Shard<Q>,
+        set: Arc<ShardSet>,
+    ) -> RunningShard {
+        let id = shard.id();
+        let sender = shard.sender();
+        let handle = tokio::spawn(gateway::runner(
+            shard,
+            self.tx.clone(),
+            self.tx_state.clone(),
+            self.cache.clone(),
+            self.runtime_config.clone(),
+            self.sessions.clone(),
+            self.event_targets.clone(),
+            set.clone(),
+            self.recorder.clone(),
+            self.guild_tracker.clone(),
+        ));
+
+        RunningShard {
+            id,
+            handle,
+            sender,
+            started: Instant::now(),
+            set,
+        }
+    }
+
+    pub async fn spawn<Q: Queue + Unpin + Send + Sync + 'static>(&self, shard: Shard<Q>) {
+        let shard_id = shard.id().number();
+        let running = self.start(shard, self.current_set.read().await.clone());
+
+        let previous = self.shards.write().await.insert(shard_id, running);
+        if let Some(previous) = previous {
+            previous.handle.abort();
+        }
+    }
+
+    /// replace a shard's runner with a fresh connection
+    /// the new connection goes through the identify queue like any other
+    pub async fn restart(&self, shard_id: u32, reason: &'static str) -> anyhow::Result<()> {
+        // take the old runner out, so the watchdog can't mistake it for one that exited by itself
+        let previous = self.shards.write().await.remove(&shard_id);
+        let retrying = self.failed_restarts.write().await.remove(&shard_id);
+        if previous.is_none() && !retrying {
+            anyhow::bail!("shard {shard_id} is not running on this node");
+        }
+
+        warn!(shard_id, reason, "restarting shard");
+        counter!(
+            "pluralkit_gateway_shard_restarts",
+            "shard_id" => shard_id.to_string(),
+            "reason" => reason,
+        )
+        .increment(1);
+
+        // stop the old runner before creating the new shard,
+        // so it can't overwrite the session we're about to resume
+        if let Some(running) = previous {
+            running.handle.abort();
+            // the old runner won't get to report its socket closing
+            if let Err(error) =
+                self.tx_state
+                    .try_send((running.id, ShardStateEvent::Closed(None), None, None))
+            {
+                error!(?error, "failed to update shard state for restart");
+            }
+        }
+
+        match gateway::create_shard(self.redis.clone(), &self.sessions, shard_id).await {
+            Ok(shard) => {
+                self.spawn(shard).await;
+                Ok(())
+            }
+            Err(error) => {
+                self.failed_restarts.write().await.insert(shard_id);
+                Err(error)
+            }
+        }
+    }
+
+    pub async fn senders(&self) -> Vec<(ShardId, MessageSender)> {
+        self.shards
+            .read()
+            .await
+            .values()
+            .map(|s| (s.id, s.sender.clone()))
+            .collect()
+    }
+
+    pub fn is_resharding(&self) -> bool {
+        self.resharding.load(Ordering::Relaxed)
+    }
+
+    /// move this node over to a different shard count without dropping events
+    ///
+    /// the new shards connect alongside the current ones, and once all of them are ready
+    /// we switch event handling over and close the old shards
+    ///
+    /// in a multi-node cluster, every node needs to be resharded (and the bot's
+    /// `TotalShards` updated), since that decides which node a guild's requests go to
+    /// until every node has switched, guilds whose shard moved to another node aren't covered,
+    /// so reshard the nodes close together
+    /// the shard count has to split evenly over the nodes, see [`gateway::check_reshard`]
+    pub async fn reshard(&self, total_shards: u32) -> anyhow::Result<()> {
+        if self
+            .resharding
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            anyhow::bail!("already resharding");
+        }
+
+        let res = self.reshard_inner(total_shards).await;
+        self.resharding.store(false, Ordering::SeqCst);
+        res
+    }
+
+    async fn reshard_inner(&self, total_shards: u32) -> anyhow::Result<()> {
+        let shards = gateway::create_reshard_set(self.redis.clone(), total_shards)?;
+        self.switch_over(total_shards, shards).await?;
+
+        self.runtime_config
+            .set(
+                gateway::RUNTIME_CONFIG_KEY_TOTAL_SHARDS.to_string(),
+                total_shards.to_string(),
+            )
+            .await?;
+
+        info!(total_shards, "resharding complete");
+        Ok(())
+    }
+
+    /// start `shards` alongside the current ones, and swap over to them once they're all ready
+    async fn switch_over<Q: Queue + Unpin + Send + Sync + 'static>(
+        &self,
+        total_shards: u32,
+        shards: Vec<Shard<Q>>,
+    ) -> anyhow::Result<()> {
+        let set = Arc::new(shard_set(false));
+        let pending: HashMap<u32, RunningShard> = shards
+            .into_iter()
+            .map(|shard| (shard.id().number(), self.start(shard, set.clone())))
+            .collect();
+
+        info!(
+            total_shards,
+            "started {} new shards, waiting for them to be ready",
+            pending.len()
+        );
+
+        let deadline = Instant::now() + RESHARD_TIMEOUT;
+        while set.ready.read().await.len() < pending.len() {
+            if Instant::now() > deadline || pending.values().any(|s| s.handle.is_finished()) {
+                for running in pending.values() {
+                    running.handle.abort();
+                }
+                anyhow::bail!("new shards didn't become ready, keeping the current shards");
+            }
+            // still wake up now and then, to notice shards that exited
+            let _ = tokio::time::timeout(RESHARD_POLL_INTERVAL, set.became_ready.notified()).await;
+        }
+
+        // switch over
+        // the old shards stop before the new ones start, so the bot never sees an event twice
+        let old = {
+            let mut shards = self.shards.write().await;
+            for running in shards.values() {
+                running.set.active.store(false, Ordering::SeqCst);
+            }
+            gateway::set_total_shards(total_shards);
+            // these were for the old shard count
+            self.failed_restarts.write().await.clear();
+            set.active.store(true, Ordering::SeqCst);
+            *self.current_set.write().await = set.clone();
+
+            let mut pending = pending;
+            for running in pending.values_mut() {
+                // don't let the watchdog judge them on the old shards' heartbeats
+                running.started = Instant::now();
+            }
+            std::mem::replace(&mut *shards, pending)
+        };
+
+        for running in old.values() {
+            running.handle.abort();
+        }
+        *self.cache.2.write().await = set.ready.read().await.iter().copied().collect();
+
+        Ok(())
+    }
+
+    pub async fn abort_all(&self) {
+        for running in self.shards.read().await.values() {
+            running.handle.abort();
+        }
+    }
+
+    /// restarts shards that look connected, but haven't had a heartbeat ACK in a while
+    ///
+    /// returns if a runner exits by itself (the shard hit a fatal close code),
+    /// so that the gateway shuts down like it did before
+    /// runners we stopped ourselves are never in `shards`, so they don't count
+    pub async fn watchdog_loop(&self, shard_state: Arc<ShardStateManager>) {
+        loop {
+            tokio::time::sleep(WATCHDOG_INTERVAL).await;
+
+            let failed: Vec<u32> = self.failed_restarts.read().await.iter().copied().collect();
+            for shard_id in failed {
+                if let Err(error) = self.restart(shard_id, "retry").await {
+                    error!(?error, shard_id, "failed to restart shard again");
+                }
+            }
+
+            if let Some(shard_id) = self
+                .shards
+                .read()
+                .await
+                .iter()
+                .find(|(_, s)| s.handle.is_finished())
+                .map(|(id, _)| *id)
+            {
+                error!(shard_id, "shard runner exited");
+                return;
+            }
+
+            let now = chrono::offset::Utc::now().timestamp();
+            for state in shard_state.get().await {
+                let shard_id = state.shard_id as u32;
+                let Some(started) = self.shards.read().await.get(&shard_id).map(|s| s.started)
+                else {
+                    continue;
+                };
+                if started.elapsed() < RESTART_GRACE || !state.up {
+                    continue;
+                }
+
+                // a shard that connected but never got an ACK counts from when it connected
+                let last_seen = state.last_heartbeat.max(state.last_connection) as i64;
+                if now - last_seen < ZOMBIE_THRESHOLD {
+                    continue;
+                }
+
+                info!(
+                    shard_id,
+                    seconds = now - last_seen,
+                    "shard hasn't heartbeated, assuming zombied"
+                );
+                if let Err(error) = self.restart(shard_id, "zombie").await {
+                    error!(?error, shard_id, "failed to restart zombied shard");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        discord::{cache, sessions},
+        event_metrics::guild_tracker,
+        event_targets,
+        test_util::{self, message_create},
+    };
+    use serde_json::{Value, json};
+    use tokio::sync::mpsc::channel;
+    use twilight_gateway::{ConfigBuilder, queue::InMemoryQueue};
+    use twilight_model::gateway::Intents;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn message_on_shard(shard_id: u32, message_id: u64, after_ms: u64) -> Value {
+        let mut step = message_create(message_id);
+        step["shard"] = json!(shard_id);
+        step["after_ms"] = json!(after_ms);
+        step
+    }
+
+    #[tokio::test]
+    async fn reshard_switches_over_once_new_shards_are_ready() {
+        test_util::init_config();
+
+        // scenario progress is kept per shard id, so the first message is only sent by the old
+        // shard 0, and the second one by the new shard 1, a while after it's ready
+        let url = test_util::fake_gateway(json!({
+            "steps": [message_on_shard(0, 1, 0), message_on_shard(1, 2, 1000)],
+        }))
+        .await;
+
+        let runtime_config = test_util::runtime_config();
+        let (tx, mut rx) = channel(100);
+        let (tx_state, _rx_state) = channel(100);
+        let supervisor = new(
+            test_util::redis(),
+            tx,
+            tx_state,
+            Arc::new(cache::new()),
+            runtime_config.clone(),
+            Arc::new(sessions::new(test_util::redis())),
+            Arc::new(event_targets::new(runtime_config)),
+            None,
+            Arc::new(guild_tracker(0)),
+        );
+
+        // every shard gets its own bucket, so none of them wait to identify
+        let shard = |number, total| {
+            let config = ConfigBuilder::new("token".to_string(), Intents::GUILD_MESSAGES)
+                .proxy_url(url.clone())
+                .queue(InMemoryQueue::new(16, 1000, Duration::from_secs(60), 1000))
+                .build();
+            Shard::with_config(ShardId::new(number, total), config)
+        };
+
+        supervisor.spawn(shard(0, 1)).await;
+        let old_set = supervisor.current_set.read().await.clone();
+        let (_, old_sender) = supervisor.senders().await.pop().unwrap();
+
+        let (shard_id, ..) = tokio::time::timeout(TIMEOUT, rx.recv())
+            .await
+            .expect("timed out waiting for the old shard")
+            .unwrap();
+        assert_eq!(shard_id, ShardId::new(0, 1));
+
+        tokio::time::timeout(
+            TIMEOUT,
+            supervisor.switch_over(2, vec![shard(0, 2), shard(1, 2)]),
+        )
+        .await
+        .expect("timed out waiting for the new shards")
+        .unwrap();
+
+        assert!(!old_set.is_active());
+        assert!(supervisor.current_set.read().await.is_active());
+        let senders = supervisor.senders().await;
+        assert_eq!(senders.len(), 2);
+        assert!(senders.iter().all(|(id, _)| id.total() == 2));
+
+        // events now come from the new shards
+        let (shard_id, event, ..) = tokio::time::timeout(TIMEOUT, rx.recv())
+            .await
+            .expect("timed out waiting for the new shards' events")
+            .unwrap();
+        assert_eq!(shard_id, ShardId::new(1, 2));
+        let Event::MessageCreate(message) = event else {
+            panic!("expected a MESSAGE_CREATE, got {:?}", event.kind());
+        };
+        assert_eq!(message.id.get(), 2);
+
+        // and the old shard's connection is gone
+        tokio::time::timeout(TIMEOUT, async {
+            while !old_sender.is_closed() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("old shard wasn't closed");
+    }
+}
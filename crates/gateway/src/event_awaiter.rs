@@ -1,108 +1,443 @@
-// - reaction: (message_id, user_id)
-// - message: (author_id, channel_id, ?options)
+// - reaction: (message_id, ?user_ids, ?emojis)
+// - message: (channel_id, ?author_ids, ?options / prefixes / pattern)
 // - interaction: (custom_id where not_includes "help-menu")
+//
+// every registration gets an id, which can be used to cancel it
+// if more than one registration matches an event, the newest one gets it
+// registrations are also stored in redis (per shard), so they survive a restart
+// (except in replay mode, where they're only kept in memory)
 
 use std::{
-    collections::{HashMap, hash_map::Entry},
+    collections::HashMap,
+    hash::Hash,
     net::{IpAddr, SocketAddr},
     time::Duration,
 };
 
-use serde::Deserialize;
+use fred::{
+    clients::RedisPool,
+    interfaces::{HashesInterface, KeysInterface},
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use tokio::{sync::RwLock, time::Instant};
-use tracing::info;
+use tracing::{error, info};
 use twilight_gateway::Event;
 use twilight_model::{
     application::interaction::InteractionData,
+    channel::message::EmojiReactionType,
     id::{
         Id,
         marker::{ChannelMarker, MessageMarker, UserMarker},
     },
 };
+use uuid::Uuid;
+
+use crate::discord::{cache::shard_for_guild, gateway::cluster_config};
 
 static DEFAULT_TIMEOUT: Duration = Duration::from_mins(15);
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum AwaitEventRequest {
     Reaction {
         message_id: Id<MessageMarker>,
-        user_id: Id<UserMarker>,
+        user_id: Option<Id<UserMarker>>,
+        // if no users are given, reactions from anyone match
+        #[serde(default)]
+        user_ids: Vec<Id<UserMarker>>,
+        // unicode emoji, or custom emoji ids / names
+        #[serde(default)]
+        emojis: Vec<String>,
         target: String,
         timeout: Option<u64>,
+        // 0 for DMs
+        guild_id: Option<u64>,
     },
     Message {
         channel_id: Id<ChannelMarker>,
-        author_id: Id<UserMarker>,
+        author_id: Option<Id<UserMarker>>,
+        #[serde(default)]
+        author_ids: Vec<Id<UserMarker>>,
+        // if any of these are given, the message has to match at least one of them
+        // (all case-insensitive, except for `pattern`)
+        options: Option<Vec<String>>,
+        prefixes: Option<Vec<String>>,
+        pattern: Option<String>,
         target: String,
         timeout: Option<u64>,
-        options: Option<Vec<String>>,
+        // 0 for DMs
+        guild_id: Option<u64>,
     },
     Interaction {
         id: String,
         target: String,
         timeout: Option<u64>,
+        shard_id: Option<u32>,
     },
 }
 
+impl AwaitEventRequest {
+    // older bot versions don't tell us where the event will come from,
+    // so those registrations are stored per node instead
+    fn redis_key(&self) -> String {
+        let shard_id = match self {
+            AwaitEventRequest::Reaction { guild_id, .. }
+            | AwaitEventRequest::Message { guild_id, .. } => {
+                guild_id.map(|id| Id::new_checked(id).map(shard_for_guild).unwrap_or(0))
+            }
+            AwaitEventRequest::Interaction { shard_id, .. } => *shard_id,
+        };
+
+        match shard_id {
+            Some(shard_id) => format!("pluralkit:gateway:awaiter:{shard_id}"),
+            None => format!(
+                "pluralkit:gateway:awaiter:node:{}",
+                cluster_config().node_id
+            ),
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        match self {
+            AwaitEventRequest::Reaction { timeout, .. }
+            | AwaitEventRequest::Message { timeout, .. }
+            | AwaitEventRequest::Interaction { timeout, .. } => timeout
+                .map(|i| Duration::from_secs(i))
+                .unwrap_or(DEFAULT_TIMEOUT),
+        }
+    }
+
+    fn target(&self) -> &str {
+        match self {
+            AwaitEventRequest::Reaction { target, .. }
+            | AwaitEventRequest::Message { target, .. }
+            | AwaitEventRequest::Interaction { target, .. } => target,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedRegistration {
+    request: AwaitEventRequest,
+    // unix millis
+    expires_at: i64,
+}
+
+impl SavedRegistration {
+    // registrations are saved right as they're made, with the full timeout
+    fn created_at(&self) -> i64 {
+        self.expires_at - self.request.timeout().as_millis() as i64
+    }
+}
+
+struct Registration {
+    request: AwaitEventRequest,
+    timeout: Instant,
+    redis_key: String,
+    pattern: Option<Regex>,
+}
+
+fn matches_users(
+    user_id: Id<UserMarker>,
+    single: &Option<Id<UserMarker>>,
+    list: &Vec<Id<UserMarker>>,
+) -> bool {
+    (single.is_none() && list.is_empty())
+        || single.is_some_and(|id| id == user_id)
+        || list.contains(&user_id)
+}
+
+impl Registration {
+    fn matches(&self, event: &Event) -> bool {
+        match (&self.request, event) {
+            (
+                AwaitEventRequest::Message {
+                    author_id,
+                    author_ids,
+                    options,
+                    prefixes,
+                    ..
+                },
+                Event::MessageCreate(message),
+            ) => {
+                if !matches_users(message.author.id, author_id, author_ids) {
+                    return false;
+                }
+
+                if options.is_none() && prefixes.is_none() && self.pattern.is_none() {
+                    return true;
+                }
+
+                let content = message.content.to_lowercase();
+                options.as_ref().is_some_and(|v| v.contains(&content))
+                    || prefixes
+                        .as_ref()
+                        .is_some_and(|v| v.iter().any(|p| content.starts_with(&p.to_lowercase())))
+                    || self
+                        .pattern
+                        .as_ref()
+                        .is_some_and(|p| p.is_match(&message.content))
+            }
+            (
+                AwaitEventRequest::Reaction {
+                    user_id,
+                    user_ids,
+                    emojis,
+                    ..
+                },
+                Event::ReactionAdd(reaction),
+            ) => {
+                if !matches_users(reaction.user_id, user_id, user_ids) {
+                    return false;
+                }
+
+                if emojis.is_empty() {
+                    return true;
+                }
+
+                match &reaction.emoji {
+                    EmojiReactionType::Custom { id, name, .. } => emojis
+                        .iter()
+                        .any(|e| *e == id.to_string() || name.as_ref() == Some(e)),
+                    EmojiReactionType::Unicode { name } => emojis.contains(name),
+                }
+            }
+            (AwaitEventRequest::Interaction { .. }, Event::InteractionCreate(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct AwaiterState {
+    registrations: HashMap<String, Registration>,
+    // indexes into `registrations`, oldest first
+    reactions: HashMap<Id<MessageMarker>, Vec<String>>,
+    messages: HashMap<Id<ChannelMarker>, Vec<String>>,
+    interactions: HashMap<String, Vec<String>>,
+}
+
+impl AwaiterState {
+    fn insert(&mut self, id: String, registration: Registration) {
+        match &registration.request {
+            AwaitEventRequest::Reaction { message_id, .. } => {
+                self.reactions
+                    .entry(*message_id)
+                    .or_default()
+                    .push(id.clone());
+            }
+            AwaitEventRequest::Message { channel_id, .. } => {
+                self.messages
+                    .entry(*channel_id)
+                    .or_default()
+                    .push(id.clone());
+            }
+            AwaitEventRequest::Interaction { id: custom_id, .. } => {
+                self.interactions
+                    .entry(custom_id.clone())
+                    .or_default()
+                    .push(id.clone());
+            }
+        }
+        self.registrations.insert(id, registration);
+    }
+
+    fn remove(&mut self, id: &str) -> Option<Registration> {
+        let registration = self.registrations.remove(id)?;
+
+        fn unindex<K: Eq + Hash>(index: &mut HashMap<K, Vec<String>>, key: K, id: &str) {
+            if let Some(ids) = index.get_mut(&key) {
+                ids.retain(|v| v != id);
+                if ids.is_empty() {
+                    index.remove(&key);
+                }
+            }
+        }
+
+        match &registration.request {
+            AwaitEventRequest::Reaction { message_id, .. } => {
+                unindex(&mut self.reactions, *message_id, id)
+            }
+            AwaitEventRequest::Message { channel_id, .. } => {
+                unindex(&mut self.messages, *channel_id, id)
+            }
+            AwaitEventRequest::Interaction { id: custom_id, .. } => {
+                unindex(&mut self.interactions, custom_id.clone(), id)
+            }
+        }
+
+        Some(registration)
+    }
+
+    /// newest first
+    fn candidates(&self, event: &Event) -> Vec<String> {
+        let ids = match event {
+            Event::MessageCreate(message) => self.messages.get(&message.channel_id),
+            Event::ReactionAdd(reaction) => self.reactions.get(&reaction.message_id),
+            Event::InteractionCreate(interaction)
+                if let Some(InteractionData::MessageComponent(component)) =
+                    interaction.data.as_ref()
+                    && !component.custom_id.contains("help-menu") =>
+            {
+                self.interactions.get(&component.custom_id)
+            }
+            _ => None,
+        };
+
+        ids.map(|ids| ids.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
 pub struct EventAwaiter {
-    reactions: RwLock<HashMap<(Id<MessageMarker>, Id<UserMarker>), (Instant, String)>>,
-    messages: RwLock<
-        HashMap<(Id<ChannelMarker>, Id<UserMarker>), (Instant, String, Option<Vec<String>>)>,
-    >,
-    interactions: RwLock<HashMap<String, (Instant, String)>>,
+    // None if registrations shouldn't be saved
+    redis: Option<RedisPool>,
+    state: RwLock<AwaiterState>,
 }
 
 impl EventAwaiter {
-    pub fn new() -> Self {
+    pub fn new(redis: Option<RedisPool>) -> Self {
         let v = Self {
-            reactions: RwLock::new(HashMap::new()),
-            messages: RwLock::new(HashMap::new()),
-            interactions: RwLock::new(HashMap::new()),
+            redis,
+            state: RwLock::new(AwaiterState::default()),
         };
 
         v
     }
 
+    /// reload registrations for the shards this node is running
+    pub async fn load(&self, shard_ids: impl Iterator<Item = u32>) {
+        let Some(redis) = self.redis.as_ref() else {
+            return;
+        };
+
+        let keys = shard_ids
+            .map(|id| format!("pluralkit:gateway:awaiter:{id}"))
+            .chain(std::iter::once(format!(
+                "pluralkit:gateway:awaiter:node:{}",
+                cluster_config().node_id
+            )));
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut loaded = Vec::new();
+        for key in keys {
+            let saved: HashMap<String, String> = match redis.hgetall(&key).await {
+                Ok(saved) => saved,
+                Err(error) => {
+                    error!(?error, key, "failed to load event awaiter registrations");
+                    continue;
+                }
+            };
+
+            for (field, value) in saved {
+                let saved = match serde_json::from_str::<SavedRegistration>(&value) {
+                    Ok(saved) => saved,
+                    Err(error) => {
+                        error!(
+                            ?error,
+                            key, field, "failed to parse event awaiter registration"
+                        );
+                        continue;
+                    }
+                };
+
+                if saved.expires_at <= now {
+                    continue;
+                }
+
+                loaded.push((field, saved));
+            }
+        }
+
+        // insert in the order they were made, so newer registrations still win
+        loaded.sort_by_key(|(_, saved)| saved.created_at());
+
+        let mut count = 0;
+        for (field, saved) in loaded {
+            let timeout = Instant::now() + Duration::from_millis((saved.expires_at - now) as u64);
+            if let Err(error) = self.insert(field.clone(), saved.request, timeout).await {
+                error!(?error, field, "failed to load event awaiter registration");
+                continue;
+            }
+            count += 1;
+        }
+
+        info!("loaded {count} event awaiter registrations");
+    }
+
+    async fn persist(&self, id: &str, request: &AwaitEventRequest) -> anyhow::Result<()> {
+        let Some(redis) = self.redis.as_ref() else {
+            return Ok(());
+        };
+
+        let timeout = request.timeout();
+        let saved = SavedRegistration {
+            request: request.clone(),
+            expires_at: chrono::Utc::now().timestamp_millis() + timeout.as_millis() as i64,
+        };
+
+        let key = request.redis_key();
+        redis
+            .hset::<(), _, _>(&key, (id.to_string(), serde_json::to_string(&saved)?))
+            .await?;
+
+        // hash fields can't expire on their own, so expired entries are removed in
+        // `cleanup_loop` (or skipped on load) - this just makes sure the key doesn't stick around
+        // if we stop using it
+        let ttl: i64 = redis.ttl(&key).await?;
+        if ttl < timeout.as_secs() as i64 {
+            redis
+                .expire::<(), _>(&key, timeout.as_secs() as i64)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn unpersist(&self, id: &str, registration: &Registration) {
+        let Some(redis) = self.redis.as_ref() else {
+            return;
+        };
+
+        if let Err(error) = redis
+            .hdel::<(), _, _>(&registration.redis_key, id.to_string())
+            .await
+        {
+            error!(?error, "failed to delete event awaiter registration");
+        }
+    }
+
     pub async fn cleanup_loop(&self) {
         loop {
             tokio::time::sleep(Duration::from_secs(30)).await;
             info!("running event_awaiter cleanup loop");
-            let mut counts = (0, 0, 0);
             let now = Instant::now();
+            let mut counts = (0, 0, 0);
+            let mut expired = Vec::new();
             {
-                let mut reactions = self.reactions.write().await;
-                for key in reactions.clone().keys() {
-                    if let Entry::Occupied(entry) = reactions.entry(key.clone())
-                        && entry.get().0 < now
-                    {
-                        counts.0 += 1;
-                        entry.remove();
-                    }
-                }
-            }
-            {
-                let mut messages = self.messages.write().await;
-                for key in messages.clone().keys() {
-                    if let Entry::Occupied(entry) = messages.entry(key.clone())
-                        && entry.get().0 < now
-                    {
-                        counts.1 += 1;
-                        entry.remove();
+                let mut state = self.state.write().await;
+                let ids: Vec<String> = state
+                    .registrations
+                    .iter()
+                    .filter(|(_, v)| v.timeout < now)
+                    .map(|(id, _)| id.clone())
+                    .collect();
+                for id in ids {
+                    let registration = state.remove(&id).unwrap();
+                    match registration.request {
+                        AwaitEventRequest::Reaction { .. } => counts.0 += 1,
+                        AwaitEventRequest::Message { .. } => counts.1 += 1,
+                        AwaitEventRequest::Interaction { .. } => counts.2 += 1,
                     }
+                    expired.push((id, registration));
                 }
             }
-            {
-                let mut interactions = self.interactions.write().await;
-                for key in interactions.clone().keys() {
-                    if let Entry::Occupied(entry) = interactions.entry(key.clone())
-                        && entry.get().0 < now
-                    {
-                        counts.2 += 1;
-                        entry.remove();
-                    }
-                }
+
+            for (id, registration) in expired.iter() {
+                self.unpersist(id, registration).await;
             }
+
             info!(
                 "ran event_awaiter cleanup loop, took {}us, {} reactions, {} messages, {} interactions",
                 Instant::now().duration_since(now).as_micros(),
@@ -114,118 +449,97 @@ impl EventAwaiter {
     }
 
     pub async fn target_for_event(&self, event: Event) -> Option<String> {
-        match event {
-            Event::MessageCreate(message) => {
-                let mut messages = self.messages.write().await;
-
-                messages
-                    .remove(&(message.channel_id, message.author.id))
-                    .map(|(timeout, target, options)| {
-                        if let Some(options) = options
-                            && !options.contains(&message.content.to_lowercase())
-                        {
-                            messages.insert(
-                                (message.channel_id, message.author.id),
-                                (timeout, target, Some(options)),
-                            );
-                            return None;
-                        }
-                        Some((*target).to_string())
-                    })?
-            }
-            Event::ReactionAdd(reaction)
-                if let Some((_, target)) = self
-                    .reactions
-                    .write()
-                    .await
-                    .remove(&(reaction.message_id, reaction.user_id)) =>
-            {
-                Some((*target).to_string())
-            }
-            Event::InteractionCreate(interaction)
-                if let Some(data) = interaction.data.clone()
-                    && let InteractionData::MessageComponent(component) = data
-                    && !component.custom_id.contains("help-menu")
-                    && let Some((_, target)) =
-                        self.interactions.write().await.remove(&component.custom_id) =>
-            {
-                Some((*target).to_string())
-            }
+        let now = Instant::now();
+        let (id, registration) = {
+            let mut state = self.state.write().await;
+            // expired registrations stick around until the next cleanup, but shouldn't match
+            let id = state.candidates(&event).into_iter().find(|id| {
+                state
+                    .registrations
+                    .get(id)
+                    .is_some_and(|r| r.timeout > now && r.matches(&event))
+            })?;
+            let registration = state.remove(&id)?;
+            (id, registration)
+        };
 
-            _ => None,
-        }
+        self.unpersist(&id, &registration).await;
+        Some(registration.request.target().to_string())
     }
 
-    pub async fn handle_request(&self, req: AwaitEventRequest, addr: SocketAddr) {
-        match req {
-            AwaitEventRequest::Reaction {
-                message_id,
-                user_id,
-                target,
-                timeout,
-            } => {
-                self.reactions.write().await.insert(
-                    (message_id, user_id),
-                    (
-                        Instant::now()
-                            .checked_add(
-                                timeout
-                                    .map(|i| Duration::from_secs(i))
-                                    .unwrap_or(DEFAULT_TIMEOUT),
-                            )
-                            .expect("invalid time"),
-                        target_or_addr(target, addr),
-                    ),
-                );
-            }
+    async fn insert(
+        &self,
+        id: String,
+        request: AwaitEventRequest,
+        timeout: Instant,
+    ) -> anyhow::Result<()> {
+        let pattern = match &request {
             AwaitEventRequest::Message {
-                channel_id,
-                author_id,
-                target,
-                timeout,
-                options,
-            } => {
-                self.messages.write().await.insert(
-                    (channel_id, author_id),
-                    (
-                        Instant::now()
-                            .checked_add(
-                                timeout
-                                    .map(|i| Duration::from_secs(i))
-                                    .unwrap_or(DEFAULT_TIMEOUT),
-                            )
-                            .expect("invalid time"),
-                        target_or_addr(target, addr),
-                        options,
-                    ),
-                );
-            }
-            AwaitEventRequest::Interaction {
-                id,
-                target,
-                timeout,
-            } => {
-                self.interactions.write().await.insert(
-                    id,
-                    (
-                        Instant::now()
-                            .checked_add(
-                                timeout
-                                    .map(|i| Duration::from_secs(i))
-                                    .unwrap_or(DEFAULT_TIMEOUT),
-                            )
-                            .expect("invalid time"),
-                        target_or_addr(target, addr),
-                    ),
-                );
+                pattern: Some(pattern),
+                ..
+            } => Some(Regex::new(pattern)?),
+            _ => None,
+        };
+
+        let registration = Registration {
+            redis_key: request.redis_key(),
+            request,
+            timeout,
+            pattern,
+        };
+
+        let mut state = self.state.write().await;
+        state.remove(&id);
+        state.insert(id, registration);
+
+        Ok(())
+    }
+
+    /// returns the id of the registration
+    pub async fn handle_request(
+        &self,
+        mut req: AwaitEventRequest,
+        addr: SocketAddr,
+    ) -> anyhow::Result<String> {
+        match &mut req {
+            AwaitEventRequest::Reaction { target, .. }
+            | AwaitEventRequest::Message { target, .. }
+            | AwaitEventRequest::Interaction { target, .. } => {
+                *target = target_or_addr(target.clone(), addr);
             }
         }
+
+        let id = Uuid::new_v4().to_string();
+        let timeout = Instant::now()
+            .checked_add(req.timeout())
+            .expect("invalid time");
+
+        // this also validates the request (regex), so do it before saving
+        self.insert(id.clone(), req.clone(), timeout).await?;
+
+        if let Err(error) = self.persist(&id, &req).await {
+            error!(?error, "failed to save event awaiter registration");
+        }
+
+        Ok(id)
+    }
+
+    /// returns false if there was no registration with this id
+    pub async fn cancel(&self, id: &str) -> bool {
+        let Some(registration) = self.state.write().await.remove(id) else {
+            return false;
+        };
+
+        self.unpersist(id, &registration).await;
+        true
     }
 
     pub async fn clear(&self) {
-        self.reactions.write().await.clear();
-        self.messages.write().await.clear();
-        self.interactions.write().await.clear();
+        let cleared = std::mem::take(&mut *self.state.write().await);
+
+        for (id, registration) in cleared.registrations.iter() {
+            self.unpersist(id, registration).await;
+        }
     }
 }
 
@@ -246,3 +560,236 @@ fn target_or_addr(target: String, addr: SocketAddr) -> String {
         target
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{init_config, message_create};
+    use serde_json::{Value, json};
+    use twilight_model::gateway::payload::incoming::{MessageCreate, ReactionAdd};
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:5002".parse().unwrap()
+    }
+
+    fn awaiter() -> EventAwaiter {
+        init_config();
+        EventAwaiter::new(None)
+    }
+
+    async fn register(awaiter: &EventAwaiter, request: Value) -> String {
+        awaiter
+            .handle_request(serde_json::from_value(request).unwrap(), addr())
+            .await
+            .unwrap()
+    }
+
+    // a message in channel 10
+    fn message(author_id: u64, content: &str) -> Event {
+        let mut message = message_create(1)["d"].clone();
+        message["author"]["id"] = json!(author_id.to_string());
+        message["content"] = json!(content);
+        Event::MessageCreate(Box::new(
+            serde_json::from_value::<MessageCreate>(message).unwrap(),
+        ))
+    }
+
+    // a reaction on message 5
+    fn reaction(user_id: u64, emoji: Value) -> Event {
+        Event::ReactionAdd(Box::new(
+            serde_json::from_value::<ReactionAdd>(json!({
+                "channel_id": "10",
+                "message_id": "5",
+                "user_id": user_id.to_string(),
+                "emoji": emoji,
+                "burst": false,
+                "burst_colors": [],
+            }))
+            .unwrap(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn matches_message_options_and_prefixes() {
+        let awaiter = awaiter();
+        register(
+            &awaiter,
+            json!({ "channel_id": "10", "options": ["yes", "no"], "prefixes": ["pk;"], "target": "a" }),
+        )
+        .await;
+
+        assert_eq!(awaiter.target_for_event(message(20, "maybe")).await, None);
+        // case-insensitive
+        assert_eq!(
+            awaiter
+                .target_for_event(message(20, "YES"))
+                .await
+                .as_deref(),
+            Some("a")
+        );
+        // only matches once
+        assert_eq!(awaiter.target_for_event(message(20, "yes")).await, None);
+
+        register(
+            &awaiter,
+            json!({ "channel_id": "10", "prefixes": ["pk;"], "target": "b" }),
+        )
+        .await;
+        assert_eq!(
+            awaiter
+                .target_for_event(message(20, "PK;member"))
+                .await
+                .as_deref(),
+            Some("b")
+        );
+    }
+
+    #[tokio::test]
+    async fn matches_message_pattern() {
+        let awaiter = awaiter();
+        register(
+            &awaiter,
+            json!({ "channel_id": "10", "pattern": "^[0-9]+$", "target": "a" }),
+        )
+        .await;
+
+        assert_eq!(awaiter.target_for_event(message(20, "12a")).await, None);
+        assert_eq!(
+            awaiter
+                .target_for_event(message(20, "123"))
+                .await
+                .as_deref(),
+            Some("a")
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_patterns() {
+        let awaiter = awaiter();
+        let request = json!({ "channel_id": "10", "pattern": "(", "target": "a" });
+        assert!(
+            awaiter
+                .handle_request(serde_json::from_value(request).unwrap(), addr())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn matches_message_authors() {
+        let awaiter = awaiter();
+        register(
+            &awaiter,
+            json!({ "channel_id": "10", "author_id": "20", "author_ids": ["21"], "target": "a" }),
+        )
+        .await;
+
+        assert_eq!(awaiter.target_for_event(message(22, "hi")).await, None);
+        assert_eq!(
+            awaiter.target_for_event(message(21, "hi")).await.as_deref(),
+            Some("a")
+        );
+    }
+
+    #[tokio::test]
+    async fn matches_reactions_from_any_listed_user() {
+        let awaiter = awaiter();
+        let request = json!({ "message_id": "5", "user_ids": ["20", "21"], "target": "a" });
+        register(&awaiter, request.clone()).await;
+        register(&awaiter, request).await;
+
+        let emoji = json!({ "name": "✅" });
+        assert_eq!(
+            awaiter.target_for_event(reaction(22, emoji.clone())).await,
+            None
+        );
+        assert_eq!(
+            awaiter
+                .target_for_event(reaction(20, emoji.clone()))
+                .await
+                .as_deref(),
+            Some("a")
+        );
+        assert_eq!(
+            awaiter
+                .target_for_event(reaction(21, emoji))
+                .await
+                .as_deref(),
+            Some("a")
+        );
+    }
+
+    #[tokio::test]
+    async fn matches_reaction_emojis() {
+        let awaiter = awaiter();
+        register(
+            &awaiter,
+            json!({ "message_id": "5", "emojis": ["✅", "30", "pk"], "target": "a" }),
+        )
+        .await;
+        register(
+            &awaiter,
+            json!({ "message_id": "5", "emojis": ["pk"], "target": "b" }),
+        )
+        .await;
+
+        assert_eq!(
+            awaiter
+                .target_for_event(reaction(20, json!({ "name": "❌" })))
+                .await,
+            None
+        );
+        // custom emoji by name, then by id
+        assert_eq!(
+            awaiter
+                .target_for_event(reaction(20, json!({ "id": "31", "name": "pk" })))
+                .await
+                .as_deref(),
+            Some("b")
+        );
+        assert_eq!(
+            awaiter
+                .target_for_event(reaction(20, json!({ "id": "30", "name": "other" })))
+                .await
+                .as_deref(),
+            Some("a")
+        );
+    }
+
+    #[tokio::test]
+    async fn newest_registration_wins() {
+        let awaiter = awaiter();
+        for target in ["a", "b", "c"] {
+            register(&awaiter, json!({ "channel_id": "10", "target": target })).await;
+        }
+
+        for target in ["c", "b", "a"] {
+            assert_eq!(
+                awaiter.target_for_event(message(20, "hi")).await.as_deref(),
+                Some(target)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn expired_registrations_dont_match() {
+        let awaiter = awaiter();
+        register(
+            &awaiter,
+            json!({ "channel_id": "10", "timeout": 0, "target": "a" }),
+        )
+        .await;
+
+        assert_eq!(awaiter.target_for_event(message(20, "hi")).await, None);
+    }
+
+    #[tokio::test]
+    async fn cancelled_registrations_dont_match() {
+        let awaiter = awaiter();
+        let id = register(&awaiter, json!({ "channel_id": "10", "target": "a" })).await;
+
+        assert!(awaiter.cancel(&id).await);
+        assert!(!awaiter.cancel(&id).await);
+        assert_eq!(awaiter.target_for_event(message(20, "hi")).await, None);
+    }
+}
@@ -0,0 +1,84 @@
+use metrics::gauge;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+use tokio::sync::{Mutex, mpsc::Sender};
+use twilight_model::id::{Id, marker::GuildMarker};
+
+const GUILD_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+const QUEUE_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// events per second for the busiest guilds
+///
+/// a label per guild would be far too many series, so only the top `guild_metrics_top_k`
+/// guilds of each interval get reported, and guilds that drop out are reset to 0
+/// off if `guild_metrics_top_k` is 0
+///
+/// the metrics facade can't remove a series, so the exporter keeps one for every guild that
+/// has been in the top k since the gateway started; keep k small, the busiest guilds rarely change
+pub struct GuildEventTracker {
+    top_k: usize,
+    counts: Mutex<HashMap<Id<GuildMarker>, u64>>,
+    reported: Mutex<HashSet<Id<GuildMarker>>>,
+}
+
+pub fn guild_tracker(top_k: usize) -> GuildEventTracker {
+    GuildEventTracker {
+        top_k,
+        counts: Mutex::new(HashMap::new()),
+        reported: Mutex::new(HashSet::new()),
+    }
+}
+
+impl GuildEventTracker {
+    pub async fn record(&self, guild_id: Option<Id<GuildMarker>>) {
+        if self.top_k == 0 {
+            return;
+        }
+        if let Some(guild_id) = guild_id {
+            *self.counts.lock().await.entry(guild_id).or_default() += 1;
+        }
+    }
+
+    pub async fn report_loop(&self) {
+        if self.top_k == 0 {
+            return;
+        }
+
+        loop {
+            tokio::time::sleep(GUILD_REPORT_INTERVAL).await;
+
+            let mut counts: Vec<(Id<GuildMarker>, u64)> =
+                std::mem::take(&mut *self.counts.lock().await)
+                    .into_iter()
+                    .collect();
+            counts.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+            counts.truncate(self.top_k);
+
+            let mut reported = self.reported.lock().await;
+            let top: HashSet<Id<GuildMarker>> = counts.iter().map(|(id, _)| *id).collect();
+            for guild_id in reported.difference(&top) {
+                gauge!("pluralkit_gateway_guild_event_rate", "guild_id" => guild_id.to_string())
+                    .set(0.0);
+            }
+
+            for (guild_id, count) in counts {
+                gauge!("pluralkit_gateway_guild_event_rate", "guild_id" => guild_id.to_string())
+                    .set(count as f64 / GUILD_REPORT_INTERVAL.as_secs_f64());
+            }
+            *reported = top;
+        }
+    }
+}
+
+/// how full the channels between the shards and the rest of the gateway are
+pub async fn queue_depth_loop<E, S>(event_tx: Sender<E>, state_tx: Sender<S>) {
+    loop {
+        gauge!("pluralkit_gateway_queue_depth", "queue" => "events")
+            .set((event_tx.max_capacity() - event_tx.capacity()) as f64);
+        gauge!("pluralkit_gateway_queue_depth", "queue" => "shard_state")
+            .set((state_tx.max_capacity() - state_tx.capacity()) as f64);
+        tokio::time::sleep(QUEUE_REPORT_INTERVAL).await;
+    }
+}
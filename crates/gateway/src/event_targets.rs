@@ -0,0 +1,161 @@
+// extra consumers (logging, analytics, ...) that get a subset of events alongside the bot
+//
+// configured through runtime config, as `event_target:{name}` = json:
+// { "target": "http://logger:5000/events", "events": ["MESSAGE_DELETE"], "guilds": ["123"] }
+// `events` and `guilds` are optional, and match everything if left out
+
+use libpk::runtime_config::RuntimeConfig;
+use serde::Deserialize;
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use tokio::sync::RwLock;
+use tracing::{error, info};
+use twilight_gateway::Event;
+use twilight_model::id::{Id, marker::GuildMarker};
+
+pub const RUNTIME_CONFIG_KEY_PREFIX: &'static str = "event_target:";
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NamedTarget {
+    #[serde(skip)]
+    pub name: String,
+    pub target: String,
+    #[serde(default)]
+    pub events: Option<HashSet<String>>,
+    #[serde(default)]
+    pub guilds: Option<HashSet<Id<GuildMarker>>>,
+}
+
+impl NamedTarget {
+    fn matches(&self, event: &Event) -> bool {
+        if let Some(events) = self.events.as_ref() {
+            let Ok(name) = serde_variant::to_variant_name(&event.kind()) else {
+                return false;
+            };
+            if !events.contains(name) {
+                return false;
+            }
+        }
+
+        if let Some(guilds) = self.guilds.as_ref()
+            && !event.guild_id().is_some_and(|id| guilds.contains(&id))
+        {
+            return false;
+        }
+
+        true
+    }
+}
+
+pub struct EventTargets {
+    runtime_config: Arc<RuntimeConfig>,
+    targets: RwLock<Vec<NamedTarget>>,
+}
+
+pub fn new(runtime_config: Arc<RuntimeConfig>) -> EventTargets {
+    EventTargets {
+        runtime_config,
+        targets: RwLock::new(Vec::new()),
+    }
+}
+
+impl EventTargets {
+    pub async fn reload(&self) {
+        let mut targets = Vec::new();
+        for (key, value) in self.runtime_config.get_all().await {
+            let Some(name) = key.strip_prefix(RUNTIME_CONFIG_KEY_PREFIX) else {
+                continue;
+            };
+            match serde_json::from_str::<NamedTarget>(&value) {
+                Ok(mut target) => {
+                    target.name = name.to_string();
+                    targets.push(target);
+                }
+                Err(error) => error!(?error, name, "invalid event target in runtime config"),
+            }
+        }
+
+        let mut current = self.targets.write().await;
+        if current.len() != targets.len() {
+            info!("now sending events to {} extra targets", targets.len());
+        }
+        *current = targets;
+    }
+
+    pub async fn reload_loop(&self) {
+        loop {
+            self.reload().await;
+            tokio::time::sleep(RELOAD_INTERVAL).await;
+        }
+    }
+
+    /// (name, target) of the targets that want this event
+    pub async fn matching(&self, event: &Event) -> Vec<(String, String)> {
+        self.targets
+            .read()
+            .await
+            .iter()
+            .filter(|t| t.matches(event))
+            .map(|t| (t.name.clone(), t.target.clone()))
+            .collect()
+    }
+
+    pub async fn get(&self, name: &str) -> Option<String> {
+        self.targets
+            .read()
+            .await
+            .iter()
+            .find(|t| t.name == name)
+            .map(|t| t.target.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use twilight_model::gateway::payload::incoming::MessageDelete;
+
+    fn target(mut filters: serde_json::Value) -> NamedTarget {
+        filters["target"] = json!("http://localhost/events");
+        serde_json::from_value(filters).unwrap()
+    }
+
+    fn message_delete(guild_id: Option<u64>) -> Event {
+        Event::MessageDelete(MessageDelete {
+            channel_id: Id::new(1),
+            guild_id: guild_id.map(Id::new),
+            id: Id::new(2),
+        })
+    }
+
+    #[test]
+    fn no_filters_match_everything() {
+        let target = target(json!({}));
+        assert!(target.matches(&message_delete(Some(10))));
+        assert!(target.matches(&message_delete(None)));
+    }
+
+    #[test]
+    fn filters_by_event_type() {
+        assert!(target(json!({ "events": ["MESSAGE_DELETE"] })).matches(&message_delete(None)));
+        assert!(!target(json!({ "events": ["MESSAGE_CREATE"] })).matches(&message_delete(None)));
+    }
+
+    #[test]
+    fn filters_by_guild() {
+        let target = target(json!({ "guilds": ["10"] }));
+        assert!(target.matches(&message_delete(Some(10))));
+        assert!(!target.matches(&message_delete(Some(11))));
+        // DMs aren't in any guild
+        assert!(!target.matches(&message_delete(None)));
+    }
+
+    #[test]
+    fn needs_every_filter_to_match() {
+        let target = target(json!({ "events": ["MESSAGE_DELETE"], "guilds": ["10"] }));
+        assert!(target.matches(&message_delete(Some(10))));
+        assert!(!target.matches(&message_delete(Some(11))));
+    }
+}
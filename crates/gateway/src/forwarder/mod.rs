@@ -0,0 +1,509 @@
+use fred::clients::RedisPool;
+use libpk::runtime_config::RuntimeConfig;
+use metrics::{counter, histogram};
+use reqwest::{Client, ClientBuilder, StatusCode};
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
+use tracing::{error, info, warn};
+
+use redis_stream::{StreamPublisher, StreamTarget};
+use spool::{Spool, SpooledEvent};
+
+use crate::{RUNTIME_CONFIG_KEY_EVENT_TARGET, event_targets::EventTargets};
+
+pub mod redis_stream;
+pub mod spool;
+
+const MAX_ATTEMPTS: u32 = 4;
+const BASE_BACKOFF: Duration = Duration::from_millis(250);
+const SPOOL_DRAIN_INTERVAL: Duration = Duration::from_secs(5);
+// how long to wait for a busy target when there's no spool to spill to
+const TARGET_WAIT: Duration = Duration::from_secs(1);
+
+/// sends events to the bot, retrying failed deliveries
+///
+/// targets are either an HTTP base url (events are POSTed to `{target}/{shard_id}`),
+/// or a redis stream (see [`StreamTarget`])
+///
+/// requests are only retried if they never reached the target, since the bot handles an
+/// event before responding, and sending it again after a timeout could proxy a message twice
+/// stream consumers ack separately, so failed publishes are always retried
+///
+/// events whose target stayed unreachable, or that arrive while a target is at its
+/// concurrency limit, are spilled to the spool (if configured) and replayed later
+/// without a spool, we wait a little for a busy target, then drop the event
+///
+/// spooled events are replayed to wherever their target points by then,
+/// since the bot (or an extra target) may have moved while it was down
+pub struct EventForwarder {
+    client: Client,
+    concurrency: usize,
+    limits: RwLock<HashMap<String, Arc<Semaphore>>>,
+    spool: Option<Spool>,
+    streams: StreamPublisher,
+    runtime_config: Arc<RuntimeConfig>,
+    event_targets: Arc<EventTargets>,
+    // deliveries that haven't finished (or been spooled) yet, for draining on shutdown
+    in_flight: AtomicUsize,
+    completed: AtomicU64,
+}
+
+pub async fn new(
+    spool_dir: Option<&str>,
+    redis: RedisPool,
+    runtime_config: Arc<RuntimeConfig>,
+    event_targets: Arc<EventTargets>,
+) -> anyhow::Result<EventForwarder> {
+    let config = libpk::config.discord();
+
+    let spool = match spool_dir {
+        Some(dir) => Some(spool::new(dir, config.event_spool_max_bytes).await?),
+        None => None,
+    };
+
+    Ok(EventForwarder {
+        client: ClientBuilder::new()
+            .connect_timeout(Duration::from_secs(1))
+            .timeout(Duration::from_secs(1))
+            .build()?,
+        concurrency: config.event_target_concurrency,
+        limits: RwLock::new(HashMap::new()),
+        spool,
+        streams: redis_stream::new(redis),
+        runtime_config,
+        event_targets,
+        in_flight: AtomicUsize::new(0),
+        completed: AtomicU64::new(0),
+    })
+}
+
+// by name, since awaiter targets are pod ips that change with every deploy
+fn failed(target_name: &str, reason: &'static str) {
+    counter!(
+        "pluralkit_gateway_forward_failures",
+        "target" => target_name.to_string(),
+        "reason" => reason,
+    )
+    .increment(1);
+}
+
+fn dropped(reason: &'static str) {
+    counter!("pluralkit_gateway_events_dropped", "reason" => reason).increment(1);
+}
+
+enum Delivery {
+    Sent,
+    // the target doesn't want this event, trying again won't help
+    Rejected,
+    // the target might have handled the event, so it can't be sent again
+    Failed,
+    // the request never reached the target, so it's safe to send again
+    Unreachable,
+}
+
+impl EventForwarder {
+    async fn limit(&self, target: &str) -> Arc<Semaphore> {
+        if let Some(limit) = self.limits.read().await.get(target) {
+            return limit.clone();
+        }
+        self.limits
+            .write()
+            .await
+            .entry(target.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.concurrency)))
+            .clone()
+    }
+
+    pub async fn forward(self: &Arc<Self>, event: SpooledEvent) {
+        let limit = self.limit(&event.target).await;
+        let permit = if self.spool.is_some() {
+            match limit.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    self.spill(event, "target_busy").await;
+                    return;
+                }
+            }
+        } else {
+            match tokio::time::timeout(TARGET_WAIT, limit.clone().acquire_owned()).await {
+                Ok(permit) => permit.expect("target limit closed"),
+                Err(_) => {
+                    failed(&event.target_name, "busy");
+                    dropped("target_busy");
+                    return;
+                }
+            }
+        };
+
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        tokio::spawn({
+            let forwarder = self.clone();
+            async move {
+                match forwarder.deliver(&event, limit, permit).await {
+                    Delivery::Sent => {
+                        if let Some(received_at) = event.received_at {
+                            histogram!("pluralkit_gateway_event_delivery_seconds")
+                                .record(received_at.elapsed());
+                        }
+                    }
+                    Delivery::Rejected => {
+                        failed(&event.target_name, "rejected");
+                        dropped("rejected");
+                    }
+                    Delivery::Failed => {
+                        failed(&event.target_name, "error");
+                        dropped("delivery_failed");
+                    }
+                    Delivery::Unreachable => {
+                        failed(&event.target_name, "unreachable");
+                        forwarder.spill(event, "target_unreachable").await;
+                    }
+                }
+                forwarder.completed.fetch_add(1, Ordering::SeqCst);
+                forwarder.in_flight.fetch_sub(1, Ordering::SeqCst);
+            }
+        });
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// deliveries finished since startup, whether they were sent or spooled
+    pub fn completed(&self) -> u64 {
+        self.completed.load(Ordering::SeqCst)
+    }
+
+    async fn deliver(
+        &self,
+        event: &SpooledEvent,
+        limit: Arc<Semaphore>,
+        permit: OwnedSemaphorePermit,
+    ) -> Delivery {
+        let mut first = Some(permit);
+        for attempt in 0..MAX_ATTEMPTS {
+            // the permit is dropped at the end of each attempt,
+            // so other events can get through to the target while we back off
+            let _permit = match first.take() {
+                Some(permit) => permit,
+                None => {
+                    counter!("pluralkit_gateway_events_retried").increment(1);
+                    tokio::time::sleep(BASE_BACKOFF * 2u32.pow(attempt - 1)).await;
+                    limit
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .expect("target limit closed")
+                }
+            };
+
+            let res = match StreamTarget::parse(&event.target) {
+                Some(target) => self.deliver_stream(&target, event, attempt).await,
+                None => self.deliver_http(event, attempt).await,
+            };
+
+            match res {
+                Delivery::Unreachable => continue,
+                res => return res,
+            }
+        }
+
+        Delivery::Unreachable
+    }
+
+    async fn deliver_http(&self, event: &SpooledEvent, attempt: u32) -> Delivery {
+        match self
+            .client
+            .post(format!("{}/{}", event.target, event.shard_id))
+            .body(event.raw_event.clone())
+            .send()
+            .await
+        {
+            Ok(res) if res.status() == StatusCode::OK => Delivery::Sent,
+            Ok(res) if res.status().is_client_error() => {
+                error!(
+                    status = ?res.status(),
+                    target = ?event.target,
+                    "got non-200 from bot while sending event",
+                );
+                Delivery::Rejected
+            }
+            Ok(res) => {
+                error!(
+                    status = ?res.status(),
+                    target = ?event.target,
+                    "got non-200 from bot while sending event",
+                );
+                Delivery::Failed
+            }
+            Err(error) if error.is_connect() => {
+                warn!(?error, target = ?event.target, attempt, "failed to connect to event target");
+                Delivery::Unreachable
+            }
+            // timeouts and dropped connections, the bot may still be handling the event
+            Err(error) => {
+                error!(?error, target = ?event.target, "failed to request event target");
+                Delivery::Failed
+            }
+        }
+    }
+
+    async fn deliver_stream(
+        &self,
+        target: &StreamTarget,
+        event: &SpooledEvent,
+        attempt: u32,
+    ) -> Delivery {
+        match self.streams.publish(target, event).await {
+            Ok(_) => Delivery::Sent,
+            // consumers have to handle redelivery of un-acked entries anyway,
+            // so publishing twice after an ambiguous failure is fine
+            Err(error) => {
+                warn!(
+                    ?error,
+                    key = %target.key,
+                    attempt,
+                    "failed to publish event to stream"
+                );
+                Delivery::Unreachable
+            }
+        }
+    }
+
+    async fn spill(&self, event: SpooledEvent, reason: &'static str) {
+        let Some(spool) = self.spool.as_ref() else {
+            dropped(reason);
+            return;
+        };
+
+        match spool.push(&event).await {
+            Ok(true) => {
+                counter!("pluralkit_gateway_events_spooled", "reason" => reason).increment(1);
+            }
+            Ok(false) => dropped("spool_full"),
+            Err(error) => {
+                error!(?error, "failed to spool event");
+                dropped("spool_error");
+            }
+        }
+    }
+
+    // where a spooled event should go now, or None if its target is gone
+    async fn resolve_target(&self, event: &SpooledEvent) -> Option<String> {
+        match event.target_name.as_str() {
+            "bot" => {
+                self.runtime_config
+                    .get(RUNTIME_CONFIG_KEY_EVENT_TARGET)
+                    .await
+            }
+            // awaiter registrations point at the pod that made them, replay targets are static,
+            // and events spooled before we kept names only have the url
+            "awaiter" | "replay" | "" => Some(event.target.clone()),
+            name => self.event_targets.get(name).await,
+        }
+    }
+
+    /// replays the oldest spool segment
+    pub async fn drain_spool(self: &Arc<Self>) {
+        let Some(spool) = self.spool.as_ref() else {
+            return;
+        };
+        if spool.is_empty().await {
+            return;
+        }
+
+        let max_message_age =
+            Duration::from_secs(libpk::config.discord().event_spool_max_message_age);
+
+        let events = match spool.take_segment().await {
+            Ok(events) => events,
+            Err(error) => {
+                error!(?error, "failed to read event spool");
+                return;
+            }
+        };
+
+        info!("replaying {} spooled events", events.len());
+        for mut event in events {
+            // don't proxy messages long after they were sent
+            if event.message_age().is_some_and(|age| age > max_message_age) {
+                dropped("too_old");
+                continue;
+            }
+            let Some(target) = self.resolve_target(&event).await else {
+                dropped("target_removed");
+                continue;
+            };
+            event.target = target;
+            self.forward(event).await;
+        }
+    }
+
+    pub async fn drain_spool_loop(self: Arc<Self>) {
+        if self.spool.is_none() {
+            return;
+        }
+
+        loop {
+            tokio::time::sleep(SPOOL_DRAIN_INTERVAL).await;
+            self.drain_spool().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{event_targets, test_util};
+    use axum::{Router, extract::Path, routing::post};
+    use tokio::sync::mpsc::{Receiver, channel};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    async fn forwarder(spool: bool, settings: Vec<(&str, String)>) -> Arc<EventForwarder> {
+        test_util::init_config();
+
+        let runtime_config = Arc::new(RuntimeConfig::with_settings(
+            test_util::redis(),
+            "gateway".to_string(),
+            settings
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        ));
+        let event_targets = Arc::new(event_targets::new(runtime_config.clone()));
+        event_targets.reload().await;
+
+        let dir = std::env::temp_dir().join(format!("pk-spool-{}", uuid::Uuid::new_v4()));
+        let dir = spool.then(|| dir.to_string_lossy().to_string());
+        Arc::new(
+            new(
+                dir.as_deref(),
+                test_util::redis(),
+                runtime_config,
+                event_targets,
+            )
+            .await
+            .unwrap(),
+        )
+    }
+
+    // serves an event target, returns its url and the (shard id, event) pairs it gets
+    async fn target() -> (String, Receiver<(u32, String)>) {
+        let (tx, rx) = channel(100);
+        let app = Router::new().route(
+            "/{shard_id}",
+            post(move |Path(shard_id): Path<u32>, body: String| {
+                let tx = tx.clone();
+                async move {
+                    tx.send((shard_id, body)).await.unwrap();
+                    StatusCode::OK
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, rx)
+    }
+
+    fn event(target_name: &str, target: &str, raw_event: &str) -> SpooledEvent {
+        SpooledEvent {
+            shard_id: 1,
+            target_name: target_name.to_string(),
+            target: target.to_string(),
+            raw_event: raw_event.to_string(),
+            received_at: None,
+        }
+    }
+
+    // takes up every permit for the target
+    async fn fill(forwarder: &EventForwarder, target: &str) -> OwnedSemaphorePermit {
+        forwarder
+            .limit(target)
+            .await
+            .acquire_many_owned(forwarder.concurrency as u32)
+            .await
+            .unwrap()
+    }
+
+    async fn wait_for_deliveries(forwarder: &EventForwarder) {
+        tokio::time::timeout(TIMEOUT, async {
+            while forwarder.in_flight() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn spills_events_for_busy_targets() {
+        let forwarder = forwarder(true, vec![]).await;
+        let _permits = fill(&forwarder, "http://bot").await;
+
+        forwarder.forward(event("bot", "http://bot", "a")).await;
+        assert_eq!(forwarder.in_flight(), 0);
+
+        let spool = forwarder.spool.as_ref().unwrap();
+        let spooled = spool.take_segment().await.unwrap();
+        assert_eq!(spooled.len(), 1);
+        assert_eq!(spooled[0].target_name, "bot");
+        assert_eq!(spooled[0].raw_event, "a");
+        assert!(spool.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn drops_events_for_busy_targets_without_a_spool() {
+        let forwarder = forwarder(false, vec![]).await;
+        let _permits = fill(&forwarder, "http://bot").await;
+
+        // gives up instead of holding up the events behind this one
+        tokio::time::timeout(TIMEOUT, forwarder.forward(event("bot", "http://bot", "a")))
+            .await
+            .unwrap();
+        assert_eq!(forwarder.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn drains_spool_to_current_targets() {
+        let (url, mut rx) = target().await;
+        let forwarder = forwarder(
+            true,
+            vec![
+                (RUNTIME_CONFIG_KEY_EVENT_TARGET, url.clone()),
+                ("event_target:logs", format!(r#"{{ "target": "{url}" }}"#)),
+            ],
+        )
+        .await;
+
+        // spooled while the targets were somewhere else
+        let spool = forwarder.spool.as_ref().unwrap();
+        for event in [
+            event("bot", "http://old-bot", "bot"),
+            event("logs", "http://old-logs", "logs"),
+            event("removed", "http://removed", "removed"),
+        ] {
+            assert!(spool.push(&event).await.unwrap());
+        }
+
+        forwarder.drain_spool().await;
+        wait_for_deliveries(&forwarder).await;
+
+        let mut received = vec![rx.recv().await.unwrap(), rx.recv().await.unwrap()];
+        received.sort();
+        assert_eq!(
+            received,
+            vec![(1, "bot".to_string()), (1, "logs".to_string())]
+        );
+        assert!(rx.try_recv().is_err());
+        assert!(spool.is_empty().await);
+    }
+}
@@ -0,0 +1,140 @@
+use fred::{clients::RedisPool, interfaces::StreamsInterface};
+use std::collections::HashSet;
+use tokio::sync::RwLock;
+
+use super::spool::SpooledEvent;
+
+// keep streams from growing forever if nobody is consuming them
+const DEFAULT_MAX_LEN: i64 = 100_000;
+
+/// `redis-stream://{key}?group={group}&maxlen={n}`
+///
+/// consumers read with XREADGROUP on `group` and XACK what they've handled,
+/// so several bot workers can share the stream (see the bot's RedisStreamListenerService)
+///
+/// entries have a `shard_id` and the raw `event`
+#[derive(Debug, PartialEq)]
+pub struct StreamTarget {
+    pub key: String,
+    pub group: Option<String>,
+    pub max_len: i64,
+}
+
+impl StreamTarget {
+    pub fn parse(target: &str) -> Option<StreamTarget> {
+        let rest = target.strip_prefix("redis-stream://")?;
+        let (key, query) = rest.split_once('?').unwrap_or((rest, ""));
+        if key.is_empty() {
+            return None;
+        }
+
+        let mut res = StreamTarget {
+            key: key.to_string(),
+            group: None,
+            max_len: DEFAULT_MAX_LEN,
+        };
+
+        for param in query.split('&').filter(|v| !v.is_empty()) {
+            match param.split_once('=') {
+                Some(("group", group)) if !group.is_empty() => res.group = Some(group.to_string()),
+                Some(("maxlen", max_len)) => res.max_len = max_len.parse().ok()?,
+                _ => return None,
+            }
+        }
+
+        Some(res)
+    }
+}
+
+pub struct StreamPublisher {
+    redis: RedisPool,
+    // (key, group) pairs we've already made sure exist
+    groups: RwLock<HashSet<(String, String)>>,
+}
+
+pub fn new(redis: RedisPool) -> StreamPublisher {
+    StreamPublisher {
+        redis,
+        groups: RwLock::new(HashSet::new()),
+    }
+}
+
+impl StreamPublisher {
+    // consumers create the group too, but events published before the first one starts
+    // would be skipped if we didn't
+    async fn ensure_group(&self, key: &str, group: &str) -> anyhow::Result<()> {
+        let entry = (key.to_string(), group.to_string());
+        if self.groups.read().await.contains(&entry) {
+            return Ok(());
+        }
+
+        match self
+            .redis
+            .xgroup_create::<(), _, _, _>(key, group, "$", true)
+            .await
+        {
+            Ok(_) => {}
+            // the group already exists
+            Err(error) if error.details().starts_with("BUSYGROUP") => {}
+            Err(error) => return Err(error.into()),
+        }
+
+        self.groups.write().await.insert(entry);
+        Ok(())
+    }
+
+    pub async fn publish(&self, target: &StreamTarget, event: &SpooledEvent) -> anyhow::Result<()> {
+        if let Some(group) = target.group.as_ref() {
+            self.ensure_group(&target.key, group).await?;
+        }
+
+        self.redis
+            .xadd::<(), _, _, _, _>(
+                target.key.as_str(),
+                false,
+                ("MAXLEN", "~", target.max_len),
+                "*",
+                vec![
+                    ("shard_id", event.shard_id.to_string()),
+                    ("event", event.raw_event.clone()),
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_stream_targets() {
+        assert_eq!(
+            StreamTarget::parse("redis-stream://pluralkit:events?group=bot&maxlen=500"),
+            Some(StreamTarget {
+                key: "pluralkit:events".to_string(),
+                group: Some("bot".to_string()),
+                max_len: 500,
+            })
+        );
+        assert_eq!(
+            StreamTarget::parse("redis-stream://pluralkit:events"),
+            Some(StreamTarget {
+                key: "pluralkit:events".to_string(),
+                group: None,
+                max_len: DEFAULT_MAX_LEN,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_other_targets() {
+        assert_eq!(StreamTarget::parse("http://bot:5002/events"), None);
+        assert_eq!(StreamTarget::parse("redis-stream://"), None);
+        assert_eq!(StreamTarget::parse("redis-stream://key?group="), None);
+        assert_eq!(StreamTarget::parse("redis-stream://key?maxlen=lots"), None);
+        assert_eq!(StreamTarget::parse("redis-stream://key?unknown=1"), None);
+    }
+}
@@ -0,0 +1,168 @@
+use metrics::gauge;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use tokio::{fs::File, io::AsyncWriteExt, sync::Mutex};
+use tracing::error;
+
+// start a new segment once the current one gets this big
+const MAX_SEGMENT_SIZE: u64 = 1_000_000;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SpooledEvent {
+    pub shard_id: u32,
+    // "bot", "awaiter", "replay", or the extra target's name, for metrics
+    #[serde(default)]
+    pub target_name: String,
+    pub target: String,
+    pub raw_event: String,
+    // when the shard received this, for measuring delivery latency
+    // not kept for events that went through the spool
+    #[serde(skip)]
+    pub received_at: Option<Instant>,
+}
+
+// milliseconds between the unix epoch and the discord epoch
+const DISCORD_EPOCH: u64 = 1_420_070_400_000;
+
+impl SpooledEvent {
+    /// how long ago the message was sent, if this is a MESSAGE_CREATE
+    pub fn message_age(&self) -> Option<Duration> {
+        let payload: serde_json::Value = serde_json::from_str(&self.raw_event).ok()?;
+        if payload["t"].as_str()? != "MESSAGE_CREATE" {
+            return None;
+        }
+        let id: u64 = payload["d"]["id"].as_str()?.parse().ok()?;
+        let sent_at = (id >> 22) + DISCORD_EPOCH;
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        Some(Duration::from_millis(now.saturating_sub(sent_at)))
+    }
+}
+
+struct SpoolState {
+    writer: Option<(PathBuf, File, u64)>,
+    // total size of everything in the spool directory
+    bytes: u64,
+    next_segment: u64,
+}
+
+/// events we couldn't deliver, stored as newline-delimited json segments
+///
+/// segments are replayed oldest first, and deleted as soon as they've been read
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    state: Mutex<SpoolState>,
+}
+
+pub async fn new(dir: &str, max_bytes: u64) -> anyhow::Result<Spool> {
+    let dir = PathBuf::from(dir);
+    tokio::fs::create_dir_all(&dir).await?;
+
+    // pick up events spooled before a restart
+    let mut bytes = 0;
+    let mut entries = tokio::fs::read_dir(&dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        bytes += entry.metadata().await?.len();
+    }
+    gauge!("pluralkit_gateway_spool_bytes").set(bytes as f64);
+
+    Ok(Spool {
+        dir,
+        max_bytes,
+        state: Mutex::new(SpoolState {
+            writer: None,
+            bytes,
+            next_segment: 0,
+        }),
+    })
+}
+
+impl Spool {
+    /// returns false if the spool is full
+    pub async fn push(&self, event: &SpooledEvent) -> anyhow::Result<bool> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        let len = line.len() as u64;
+
+        let mut state = self.state.lock().await;
+        if state.bytes + len > self.max_bytes {
+            return Ok(false);
+        }
+
+        if state.writer.is_none() {
+            // segment names sort by creation time
+            let name = format!(
+                "{:020}-{:06}.jsonl",
+                chrono::Utc::now().timestamp_millis(),
+                state.next_segment
+            );
+            state.next_segment += 1;
+            let path = self.dir.join(name);
+            let file = File::create(&path).await?;
+            state.writer = Some((path, file, 0));
+        }
+
+        let (_, file, size) = state.writer.as_mut().unwrap();
+        file.write_all(&line).await?;
+        *size += len;
+        if *size >= MAX_SEGMENT_SIZE {
+            file.flush().await?;
+            state.writer = None;
+        }
+
+        state.bytes += len;
+        gauge!("pluralkit_gateway_spool_bytes").set(state.bytes as f64);
+
+        Ok(true)
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.state.lock().await.bytes == 0
+    }
+
+    /// removes the oldest segment from the spool, and returns the events in it
+    pub async fn take_segment(&self) -> anyhow::Result<Vec<SpooledEvent>> {
+        let mut state = self.state.lock().await;
+
+        let mut segments = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            segments.push(entry.path());
+        }
+        segments.sort();
+
+        let Some(path) = segments.into_iter().next() else {
+            state.bytes = 0;
+            return Ok(vec![]);
+        };
+
+        // don't keep writing to a segment we're about to delete
+        if let Some((current, file, _)) = state.writer.as_mut()
+            && *current == path
+        {
+            file.flush().await?;
+            state.writer = None;
+        }
+
+        let data = tokio::fs::read(&path).await?;
+        tokio::fs::remove_file(&path).await?;
+
+        state.bytes = state.bytes.saturating_sub(data.len() as u64);
+        gauge!("pluralkit_gateway_spool_bytes").set(state.bytes as f64);
+
+        Ok(data
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| match serde_json::from_slice(line) {
+                Ok(event) => Some(event),
+                Err(error) => {
+                    error!(?error, ?path, "failed to parse spooled event");
+                    None
+                }
+            })
+            .collect())
+    }
+}
@@ -1,26 +1,38 @@
 #![feature(if_let_guard)]
 #![feature(duration_constructors)]
 
-use chrono::Timelike;
 use discord::gateway::cluster_config;
 use event_awaiter::EventAwaiter;
-use fred::{clients::RedisPool, interfaces::*};
+use forwarder::{EventForwarder, spool::SpooledEvent};
 use libpk::{runtime_config::RuntimeConfig, state::ShardStateEvent};
-use reqwest::{ClientBuilder, StatusCode};
-use std::{sync::Arc, time::Duration, vec::Vec};
+use metrics::counter;
+use replay::ReplayForward;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+    vec::Vec,
+};
 use tokio::{
     signal::unix::{SignalKind, signal},
-    sync::mpsc::channel,
+    sync::mpsc::{Sender, channel},
     task::JoinSet,
 };
-use tracing::{error, info, warn};
-use twilight_gateway::{MessageSender, ShardId};
+use tracing::{error, info};
+use twilight_gateway::ShardId;
 use twilight_model::gateway::payload::outgoing::UpdatePresence;
 
 mod api;
+mod auth;
 mod discord;
 mod event_awaiter;
+mod event_metrics;
+mod event_targets;
+mod forwarder;
 mod logger;
+mod presence;
+mod replay;
+#[cfg(test)]
+mod test_util;
 
 const RUNTIME_CONFIG_KEY_EVENT_TARGET: &'static str = "event_target";
 
@@ -28,6 +40,11 @@ const RUNTIME_CONFIG_KEY_EVENT_TARGET: &'static str = "event_target";
 async fn main() -> anyhow::Result<()> {
     let redis = libpk::db::init_redis().await?;
 
+    // when replaying, we don't connect to discord, forward events to live targets,
+    // or write anything to redis ourselves
+    let replay_path = libpk::config.discord().event_replay_path.clone();
+    let replaying = replay_path.is_some();
+
     let runtime_config = Arc::new(
         RuntimeConfig::new(
             redis.clone(),
@@ -41,24 +58,59 @@ async fn main() -> anyhow::Result<()> {
     );
 
     // hacky, but needed for selfhost for now
-    if let Some(target) = libpk::config.discord().gateway_target.clone() {
+    if !replaying && let Some(target) = libpk::config.discord().gateway_target.clone() {
         runtime_config
             .set(RUNTIME_CONFIG_KEY_EVENT_TARGET.to_string(), target)
             .await?;
     }
 
+    // we've been resharded since the config was written
+    if let Some(total_shards) = runtime_config
+        .get(discord::gateway::RUNTIME_CONFIG_KEY_TOTAL_SHARDS)
+        .await
+        .and_then(|v| v.parse().ok())
+    {
+        info!(total_shards, "using shard count from runtime config");
+        discord::gateway::set_total_shards(total_shards);
+    }
+
     let cache = Arc::new(discord::cache::new());
-    let awaiter = Arc::new(EventAwaiter::new());
+    if let Some(path) = libpk::config.discord().cache_snapshot_path.as_ref()
+        && let Err(error) = discord::snapshot::restore(&cache, path).await
+    {
+        error!(?error, "failed to restore cache snapshot");
+    }
+
+    let awaiter = Arc::new(EventAwaiter::new((!replaying).then(|| redis.clone())));
     tokio::spawn({
         let awaiter = awaiter.clone();
         async move { awaiter.cleanup_loop().await }
     });
 
-    let shards = discord::gateway::create_shards(redis.clone())?;
+    let event_targets = Arc::new(event_targets::new(runtime_config.clone()));
+    event_targets.reload().await;
+    tokio::spawn({
+        let event_targets = event_targets.clone();
+        async move { event_targets.reload_loop().await }
+    });
+
+    let sessions = Arc::new(discord::sessions::new(redis.clone()));
+    let shards = match replay_path {
+        Some(_) => Vec::new(),
+        None => discord::gateway::create_shards(redis.clone(), &sessions).await?,
+    };
+    awaiter.load(shards.iter().map(|s| s.id().number())).await;
 
     // arbitrary
     // todo: make sure this doesn't fill up
-    let (event_tx, mut event_rx) = channel::<(ShardId, twilight_gateway::Event, String)>(1000);
+    let (event_tx, mut event_rx) = channel::<(
+        ShardId,
+        twilight_gateway::Event,
+        String,
+        bool,
+        Vec<(String, String)>,
+        Instant,
+    )>(1000);
 
     // todo: make sure this doesn't fill up
     let (state_tx, mut state_rx) = channel::<(
@@ -68,23 +120,80 @@ async fn main() -> anyhow::Result<()> {
         Option<i32>,
     )>(1000);
 
-    let mut senders = Vec::new();
-    let mut signal_senders = Vec::new();
+    let recorder = match libpk::config.discord().event_record_path.as_ref() {
+        Some(path) => Some(Arc::new(replay::recorder(path).await?)),
+        None => None,
+    };
+
+    let guild_tracker = Arc::new(event_metrics::guild_tracker(
+        libpk::config.discord().guild_metrics_top_k,
+    ));
+    tokio::spawn({
+        let guild_tracker = guild_tracker.clone();
+        async move { guild_tracker.report_loop().await }
+    });
+    tokio::spawn(event_metrics::queue_depth_loop(
+        event_tx.clone(),
+        state_tx.clone(),
+    ));
+
+    let supervisor = Arc::new(discord::supervisor::new(
+        redis.clone(),
+        event_tx.clone(),
+        state_tx.clone(),
+        cache.clone(),
+        runtime_config.clone(),
+        sessions.clone(),
+        event_targets.clone(),
+        recorder,
+        guild_tracker,
+    ));
+
+    let shard_state = Arc::new(discord::shard_state::new(redis.clone()));
+    // before the shards start, so their new transitions come after it
+    if let Err(error) = shard_state
+        .close_stale(shards.iter().map(|s| s.id().number()))
+        .await
+    {
+        error!(
+            ?error,
+            "failed to close out shards left up by the previous run"
+        );
+    }
 
-    let mut set = JoinSet::new();
     for shard in shards {
-        senders.push((shard.id(), shard.sender()));
-        signal_senders.push(shard.sender());
-        set.spawn(tokio::spawn(discord::gateway::runner(
-            shard,
-            event_tx.clone(),
-            state_tx.clone(),
-            cache.clone(),
-            runtime_config.clone(),
-        )));
+        supervisor.spawn(shard).await;
     }
 
-    let shard_state = Arc::new(discord::shard_state::new(redis.clone()));
+    let mut set = JoinSet::new();
+
+    if let Some(path) = replay_path {
+        let forward = libpk::config
+            .discord()
+            .event_replay_target
+            .is_some()
+            .then(|| ReplayForward {
+                total_shards: cluster_config().total_shards,
+                tx: event_tx.clone(),
+            });
+        // not in the set, so we keep serving the cache api once the replay is done
+        tokio::spawn({
+            let cache = cache.clone();
+            async move {
+                if let Err(error) = replay::replay(path, cache, forward).await {
+                    error!(?error, "failed to replay recorded events");
+                }
+            }
+        });
+    }
+
+    if !replaying {
+        set.spawn(tokio::spawn({
+            let supervisor = supervisor.clone();
+            let shard_state = shard_state.clone();
+            async move { supervisor.watchdog_loop(shard_state).await }
+        }));
+    }
 
     set.spawn(tokio::spawn({
         let shard_state = shard_state.clone();
@@ -101,15 +210,18 @@ async fn main() -> anyhow::Result<()> {
                             error!("failed to update shard state for heartbeat: {error}")
                         };
                     }
-                    ShardStateEvent::Closed => {
-                        if let Err(error) =
-                            shard_state.socket_closed(shard_id.number(), false).await
+                    ShardStateEvent::Closed(close_code) => {
+                        if let Err(error) = shard_state
+                            .socket_closed(shard_id.number(), false, close_code)
+                            .await
                         {
                             error!("failed to update shard state for closed: {error}")
                         };
                     }
-                    ShardStateEvent::Reconnect => {
-                        if let Err(error) = shard_state.socket_closed(shard_id.number(), true).await
+                    ShardStateEvent::Reconnect(close_code) => {
+                        if let Err(error) = shard_state
+                            .socket_closed(shard_id.number(), true, close_code)
+                            .await
                         {
                             error!("failed to update shard state for reconnect: {error}")
                         };
@@ -130,71 +242,118 @@ async fn main() -> anyhow::Result<()> {
         }
     }));
 
+    // spooled events are for live targets
+    let spool_dir = libpk::config
+        .discord()
+        .event_spool_dir
+        .as_deref()
+        .filter(|_| !replaying);
+    let forwarder = Arc::new(
+        forwarder::new(
+            spool_dir,
+            redis.clone(),
+            runtime_config.clone(),
+            event_targets.clone(),
+        )
+        .await?,
+    );
+    set.spawn(tokio::spawn({
+        let forwarder = forwarder.clone();
+        async move { forwarder.drain_spool_loop().await }
+    }));
+
     set.spawn(tokio::spawn({
         let runtime_config = runtime_config.clone();
         let awaiter = awaiter.clone();
 
+        let forwarder = forwarder.clone();
+
         async move {
-            let client = Arc::new(
-                ClientBuilder::new()
-                    .connect_timeout(Duration::from_secs(1))
-                    .timeout(Duration::from_secs(1))
-                    .build()
-                    .expect("error making client"),
-            );
-
-            while let Some((shard_id, parsed_event, raw_event)) = event_rx.recv().await {
+            while let Some((
+                shard_id,
+                parsed_event,
+                raw_event,
+                for_bot,
+                extra_targets,
+                received_at,
+            )) = event_rx.recv().await
+            {
+                for (name, target) in extra_targets {
+                    forwarder
+                        .forward(SpooledEvent {
+                            shard_id: shard_id.number(),
+                            target_name: name,
+                            target,
+                            raw_event: raw_event.clone(),
+                            received_at: Some(received_at),
+                        })
+                        .await;
+                }
+
+                if !for_bot {
+                    continue;
+                }
+
                 let target = if let Some(target) = awaiter.target_for_event(parsed_event).await {
                     info!(target = ?target, "sending event to awaiter");
-                    Some(target)
+                    Some(("awaiter", target))
+                } else if replaying {
+                    libpk::config
+                        .discord()
+                        .event_replay_target
+                        .clone()
+                        .map(|target| ("replay", target))
                 } else if let Some(target) =
                     runtime_config.get(RUNTIME_CONFIG_KEY_EVENT_TARGET).await
                 {
-                    Some(target)
+                    Some(("bot", target))
                 } else {
                     None
                 };
 
-                if let Some(target) = target {
-                    tokio::spawn({
-                        let client = client.clone();
-                        async move {
-                            match client
-                                .post(format!("{target}/{}", shard_id.number()))
-                                .body(raw_event)
-                                .send()
-                                .await
-                            {
-                                Ok(res) => {
-                                    if res.status() != StatusCode::OK {
-                                        error!(
-                                            status = ?res.status(),
-                                            target = ?target,
-                                            "got non-200 from bot while sending event",
-                                        );
-                                    }
-                                }
-                                Err(error) => {
-                                    error!(?error, "failed to request event target");
-                                }
-                            }
-                        }
-                    });
+                if let Some((name, target)) = target {
+                    forwarder
+                        .forward(SpooledEvent {
+                            shard_id: shard_id.number(),
+                            target_name: name.to_string(),
+                            target,
+                            raw_event,
+                            received_at: Some(received_at),
+                        })
+                        .await;
                 }
             }
         }
     }));
 
-    set.spawn(tokio::spawn(
-        async move { scheduled_task(redis, senders).await },
-    ));
+    set.spawn(tokio::spawn({
+        let supervisor = supervisor.clone();
+        let cache = cache.clone();
+        let shard_state = shard_state.clone();
+        let runtime_config = runtime_config.clone();
+        async move {
+            presence::presence_loop(redis, supervisor, cache, shard_state, runtime_config).await
+        }
+    }));
 
-    set.spawn(tokio::spawn(async move {
-        match api::run_server(cache, shard_state, runtime_config, awaiter.clone()).await {
-            Err(error) => {
-                error!(?error, "failed to serve cache api");
+    set.spawn(tokio::spawn({
+        let cache = cache.clone();
+        let supervisor = supervisor.clone();
+        async move {
+            match api::run_server(
+                cache,
+                shard_state,
+                runtime_config,
+                awaiter.clone(),
+                supervisor,
+            )
+            .await
+            {
+                Err(error) => {
+                    error!(?error, "failed to serve cache api");
+                }
+                _ => unreachable!(),
             }
-            _ => unreachable!(),
         }
     }));
 
@@ -217,59 +376,63 @@ async fn main() -> anyhow::Result<()> {
         d: discord::gateway::presence("Restarting... (please wait)", true),
     };
 
-    for sender in signal_senders.iter() {
+    for (_, sender) in supervisor.senders().await.iter() {
         let presence = presence.clone();
         let _ = sender.command(&presence);
     }
 
+    // the shards won't get to report closing, so they'd count as up until the next start
+    for (shard_id, _) in supervisor.senders().await.iter() {
+        if let Err(error) = shard_state
+            .socket_closed(shard_id.number(), false, None)
+            .await
+        {
+            error!(?error, "failed to update shard state for shutdown");
+        }
+    }
+
+    // stop reading from the shards, but keep everything else running while we drain
+    supervisor.abort_all().await;
+
+    // we don't close the shards cleanly, so these sessions can be resumed on the next start
+    if let Err(error) = sessions
+        .save_all(discord::gateway::cluster_config().total_shards)
+        .await
+    {
+        error!(?error, "failed to save shard sessions");
+    }
+
+    drain_events(&event_tx, &forwarder).await;
     set.abort_all();
 
+    // the replayed cache isn't the live one
+    if !replaying
+        && let Some(path) = libpk::config.discord().cache_snapshot_path.as_ref()
+        && let Err(error) = discord::snapshot::save(&cache, path).await
+    {
+        error!(?error, "failed to save cache snapshot");
+    }
+
     // sleep 500ms to allow everything to clean up properly
     tokio::time::sleep(Duration::from_millis(500)).await;
 
     Ok(())
 }
 
-async fn scheduled_task(redis: RedisPool, senders: Vec<(ShardId, MessageSender)>) {
-    let prefix = libpk::config.discord().bot_prefix_for_gateway.clone();
-
-    println!("{prefix}");
-
-    loop {
-        tokio::time::sleep(Duration::from_secs(
-            (60 - chrono::offset::Utc::now().second()).into(),
-        ))
-        .await;
-        info!("running per-minute scheduled tasks");
+// wait for queued events and in-flight deliveries to finish, up to `shutdown_drain_timeout`
+// deliveries to unreachable targets still go to the spool, if there is one
+async fn drain_events<T>(event_tx: &Sender<T>, forwarder: &EventForwarder) {
+    let deadline =
+        Instant::now() + Duration::from_secs(libpk::config.discord().shutdown_drain_timeout);
+    let completed_before = forwarder.completed();
+    let queued = || event_tx.max_capacity() - event_tx.capacity();
 
-        let status: Option<String> = match redis.get("pluralkit:botstatus").await {
-            Ok(val) => Some(val),
-            Err(error) => {
-                tracing::warn!(?error, "failed to fetch bot status from redis");
-                None
-            }
-        };
-
-        let presence = UpdatePresence {
-            op: twilight_model::gateway::OpCode::PresenceUpdate,
-            d: discord::gateway::presence(
-                if let Some(status) = status {
-                    format!("{prefix}help | {status}")
-                } else {
-                    format!("{prefix}help")
-                }
-                .as_str(),
-                false,
-            ),
-        };
-
-        for sender in senders.iter() {
-            match sender.1.command(&presence) {
-                Err(error) => {
-                    warn!(?error, "could not update presence on shard {}", sender.0)
-                }
-                _ => {}
-            };
-        }
+    while (queued() > 0 || forwarder.in_flight() > 0) && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
     }
+
+    let drained = forwarder.completed() - completed_before;
+    let dropped = queued() + forwarder.in_flight();
+    counter!("pluralkit_gateway_events_dropped", "reason" => "shutdown").increment(dropped as u64);
+    info!(drained, dropped, "finished draining events");
 }
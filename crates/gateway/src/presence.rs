@@ -0,0 +1,174 @@
+// the bot's activity status, updated every minute
+//
+// configured through runtime config:
+// - `presence_templates`: json list of templates, rotated through one per minute
+// - `presence_shard:{id}`: template for a single shard, instead of the rotation
+// - `presence_maintenance`: template used on every shard (with an idle status) while set
+//
+// templates can use {prefix}, {status} (from `pluralkit:botstatus`), {guild_count},
+// {shard_id}, {shard_count} and {latency}
+// {guild_count} is summed over every node, from the counts each node saves in `pluralkit:gateway:guild_counts`
+// without any templates, this shows "{prefix}help | {status}", or "{prefix}help" if there's no status
+
+use chrono::Timelike;
+use fred::{
+    clients::RedisPool,
+    interfaces::{HashesInterface, KeysInterface},
+};
+use libpk::runtime_config::RuntimeConfig;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tracing::{error, info, warn};
+use twilight_model::gateway::{OpCode, payload::outgoing::UpdatePresence};
+
+use crate::discord::{
+    cache::DiscordCache,
+    gateway::{cluster_config, presence},
+    shard_state::ShardStateManager,
+    supervisor::ShardSupervisor,
+};
+
+pub const RUNTIME_CONFIG_KEY_TEMPLATES: &'static str = "presence_templates";
+pub const RUNTIME_CONFIG_KEY_SHARD_PREFIX: &'static str = "presence_shard:";
+pub const RUNTIME_CONFIG_KEY_MAINTENANCE: &'static str = "presence_maintenance";
+
+const GUILD_COUNTS_KEY: &'static str = "pluralkit:gateway:guild_counts";
+
+struct Variables {
+    prefix: String,
+    status: Option<String>,
+    guild_count: usize,
+    shard_count: u32,
+}
+
+fn render(template: &str, vars: &Variables, shard_id: u32, latency: Option<i32>) -> String {
+    template
+        .replace("{prefix}", &vars.prefix)
+        .replace("{status}", vars.status.as_deref().unwrap_or_default())
+        .replace("{guild_count}", &vars.guild_count.to_string())
+        .replace("{shard_id}", &shard_id.to_string())
+        .replace("{shard_count}", &vars.shard_count.to_string())
+        .replace(
+            "{latency}",
+            &latency.map(|l| l.to_string()).unwrap_or("?".to_string()),
+        )
+}
+
+// save this node's guild count, and add up the counts of every node in the cluster
+// counts left behind by nodes past `total_nodes` are ignored
+async fn cluster_guild_count(redis: &RedisPool, node_guild_count: usize) -> anyhow::Result<usize> {
+    let cluster = cluster_config();
+    redis
+        .hset::<(), _, _>(
+            GUILD_COUNTS_KEY,
+            (cluster.node_id.to_string(), node_guild_count.to_string()),
+        )
+        .await?;
+
+    let counts: HashMap<String, String> = redis.hgetall(GUILD_COUNTS_KEY).await?;
+    Ok(counts
+        .into_iter()
+        .filter(|(node_id, _)| {
+            node_id
+                .parse::<u32>()
+                .is_ok_and(|node_id| node_id < cluster.total_nodes)
+        })
+        .filter_map(|(_, count)| count.parse::<usize>().ok())
+        .sum())
+}
+
+async fn templates(runtime_config: &RuntimeConfig, status: Option<&String>) -> Vec<String> {
+    if let Some(templates) = runtime_config.get(RUNTIME_CONFIG_KEY_TEMPLATES).await {
+        match serde_json::from_str::<Vec<String>>(&templates) {
+            Ok(templates) if !templates.is_empty() => return templates,
+            Ok(_) => {}
+            Err(error) => error!(?error, "invalid presence templates in runtime config"),
+        }
+    }
+
+    vec![match status {
+        Some(_) => "{prefix}help | {status}".to_string(),
+        None => "{prefix}help".to_string(),
+    }]
+}
+
+pub async fn presence_loop(
+    redis: RedisPool,
+    supervisor: Arc<ShardSupervisor>,
+    cache: Arc<DiscordCache>,
+    shard_state: Arc<ShardStateManager>,
+    runtime_config: Arc<RuntimeConfig>,
+) {
+    let prefix = libpk::config.discord().bot_prefix_for_gateway.clone();
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(
+            (60 - chrono::offset::Utc::now().second()).into(),
+        ))
+        .await;
+        info!("running per-minute scheduled tasks");
+
+        let status: Option<String> = match redis.get("pluralkit:botstatus").await {
+            Ok(val) => val,
+            Err(error) => {
+                tracing::warn!(?error, "failed to fetch bot status from redis");
+                None
+            }
+        };
+
+        let node_guild_count = cache.0.stats().guilds();
+        let guild_count = match cluster_guild_count(&redis, node_guild_count).await {
+            Ok(count) => count,
+            Err(error) => {
+                warn!(
+                    ?error,
+                    "failed to fetch cluster guild count, using this node's count"
+                );
+                node_guild_count
+            }
+        };
+
+        let vars = Variables {
+            prefix: prefix.clone(),
+            guild_count,
+            shard_count: cluster_config().total_shards,
+            status,
+        };
+
+        let maintenance = runtime_config.get(RUNTIME_CONFIG_KEY_MAINTENANCE).await;
+        let templates = templates(&runtime_config, vars.status.as_ref()).await;
+        let minute = chrono::offset::Utc::now().timestamp() / 60;
+        let rotating = &templates[minute as usize % templates.len()];
+
+        let latencies: HashMap<u32, i32> = shard_state
+            .get()
+            .await
+            .into_iter()
+            .map(|s| (s.shard_id as u32, s.latency))
+            .collect();
+
+        for (shard_id, sender) in supervisor.senders().await.iter() {
+            let shard_id = shard_id.number();
+            let latency = latencies.get(&shard_id).copied();
+
+            let (template, idle) = match maintenance.as_ref() {
+                Some(template) => (template.clone(), true),
+                None => (
+                    runtime_config
+                        .get(&format!("{RUNTIME_CONFIG_KEY_SHARD_PREFIX}{shard_id}"))
+                        .await
+                        .unwrap_or(rotating.clone()),
+                    false,
+                ),
+            };
+
+            let presence = UpdatePresence {
+                op: OpCode::PresenceUpdate,
+                d: presence(&render(&template, &vars, shard_id, latency), idle),
+            };
+
+            if let Err(error) = sender.command(&presence) {
+                warn!(?error, "could not update presence on shard {shard_id}")
+            }
+        }
+    }
+}
@@ -0,0 +1,217 @@
+// recording raw gateway payloads, and feeding them back in without connecting to discord
+//
+// with `event_record_path` set, every payload the shards receive is appended to that file
+// with `event_replay_path` set, the gateway doesn't start any shards, and instead runs the
+// recorded payloads through the cache like a shard would
+// events for the bot are only forwarded to `event_replay_target`, never the live event target
+// or extra targets, and nothing is written to redis (other than through the cache api)
+// the cache api keeps running afterwards, so the resulting cache can be inspected
+
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Instant};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+    sync::mpsc::{Receiver, Sender, channel},
+};
+use tracing::{error, info, warn};
+use twilight_gateway::{Event, EventTypeFlags, ShardId};
+
+use crate::discord::{cache::DiscordCache, gateway};
+
+#[derive(Serialize, Deserialize)]
+pub struct RecordedEvent {
+    pub shard_id: u32,
+    /// unix millis
+    pub received_at: i64,
+    pub event: String,
+}
+
+pub struct Recorder {
+    tx: Sender<String>,
+}
+
+pub async fn recorder(path: &str) -> anyhow::Result<Recorder> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+
+    // arbitrary
+    let (tx, rx) = channel(10_000);
+    tokio::spawn(write_loop(BufWriter::new(file), rx));
+
+    info!(path, "recording gateway events");
+    Ok(Recorder { tx })
+}
+
+async fn write_loop(mut file: BufWriter<File>, mut rx: Receiver<String>) {
+    while let Some(line) = rx.recv().await {
+        if let Err(error) = file.write_all(line.as_bytes()).await {
+            error!(?error, "failed to write recorded event");
+            continue;
+        }
+
+        // flush whenever we've caught up, so the file is usable while we're still running
+        if rx.is_empty()
+            && let Err(error) = file.flush().await
+        {
+            error!(?error, "failed to flush recorded events");
+        }
+    }
+}
+
+impl Recorder {
+    pub fn record(&self, shard_id: u32, raw_event: &str) {
+        let mut line = serde_json::to_string(&RecordedEvent {
+            shard_id,
+            received_at: chrono::offset::Utc::now().timestamp_millis(),
+            event: raw_event.to_string(),
+        })
+        .expect("failed to serialize recorded event");
+        line.push('\n');
+
+        // recording is best-effort, never hold up the shard for it
+        if self.tx.try_send(line).is_err() {
+            warn!(shard_id, "event recorder is behind, dropping event");
+        }
+    }
+}
+
+/// where replayed events go after updating the cache, if anywhere
+pub struct ReplayForward {
+    pub total_shards: u32,
+    pub tx: Sender<(ShardId, Event, String, bool, Vec<(String, String)>, Instant)>,
+}
+
+pub async fn replay(
+    path: String,
+    cache: Arc<DiscordCache>,
+    forward: Option<ReplayForward>,
+) -> anyhow::Result<()> {
+    let mut lines = BufReader::new(File::open(&path).await?).lines();
+
+    info!(path, "replaying recorded gateway events");
+    let mut count = 0;
+    while let Some(line) = lines.next_line().await? {
+        let recorded: RecordedEvent = match serde_json::from_str(&line) {
+            Ok(recorded) => recorded,
+            Err(error) => {
+                warn!(?error, "skipping invalid line in recording");
+                continue;
+            }
+        };
+
+        let event = match twilight_gateway::parse(recorded.event.clone(), EventTypeFlags::all()) {
+            Ok(Some(parsed)) => Event::from(parsed),
+            Ok(None) => continue,
+            Err(error) => {
+                error!(?error, "failed to parse recorded event");
+                continue;
+            }
+        };
+
+        cache.update(recorded.shard_id, &event).await;
+        count += 1;
+
+        let Some(forward) = forward.as_ref() else {
+            continue;
+        };
+
+        // extra targets are live consumers, so only the bot gets replayed events
+        if gateway::wanted_by_bot(&event) {
+            forward
+                .tx
+                .send((
+                    ShardId::new(recorded.shard_id, forward.total_shards),
+                    event,
+                    recorded.event,
+                    true,
+                    Vec::new(),
+                    Instant::now(),
+                ))
+                .await?;
+        }
+    }
+
+    info!(count, "finished replaying recorded gateway events");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discord::cache;
+    use serde_json::{Value, json};
+    use twilight_model::id::Id;
+
+    fn payload(t: &str, d: Value) -> String {
+        json!({ "op": 0, "s": 1, "t": t, "d": d }).to_string()
+    }
+
+    fn message(id: u64, content: &str) -> String {
+        payload(
+            "MESSAGE_CREATE",
+            json!({
+                "id": id.to_string(),
+                "channel_id": "10",
+                "content": content,
+                "author": { "id": "20", "username": "test", "discriminator": "0", "avatar": null },
+                "timestamp": "2025-01-01T00:00:00.000000+00:00",
+                "edited_timestamp": null,
+                "tts": false,
+                "mention_everyone": false,
+                "mentions": [],
+                "mention_roles": [],
+                "attachments": [],
+                "embeds": [],
+                "pinned": false,
+                "type": 0,
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn replayed_events_update_cache() {
+        crate::test_util::init_config();
+
+        let path = std::env::temp_dir().join(format!("pk-replay-{}.jsonl", std::process::id()));
+        let _ = tokio::fs::remove_file(&path).await;
+
+        let recorder = recorder(path.to_str().unwrap()).await.unwrap();
+        recorder.record(0, &message(1, "one"));
+        recorder.record(0, &message(2, "two"));
+        recorder.record(0, &message(3, "three"));
+        recorder.record(
+            0,
+            &payload("MESSAGE_DELETE", json!({ "id": "2", "channel_id": "10" })),
+        );
+        drop(recorder);
+
+        // the recorder writes in the background
+        for _ in 0..100 {
+            let recorded = tokio::fs::read_to_string(&path).await.unwrap_or_default();
+            if recorded.lines().count() == 4 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let cache = Arc::new(cache::new());
+        replay(path.to_str().unwrap().to_string(), cache.clone(), None)
+            .await
+            .unwrap();
+        let _ = tokio::fs::remove_file(&path).await;
+
+        let messages = cache.get_last_message(Id::new(10)).await.unwrap();
+        let messages = serde_json::to_value(&messages).unwrap();
+        let ids: Vec<&str> = messages["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, ["3", "1"]);
+    }
+}
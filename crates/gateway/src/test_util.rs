@@ -0,0 +1,86 @@
+use fake_gateway::{Settings, scenario::Scenario};
+use fred::{clients::RedisPool, types::RedisConfig};
+use libpk::runtime_config::RuntimeConfig;
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Once},
+};
+
+use crate::RUNTIME_CONFIG_KEY_EVENT_TARGET;
+
+static INIT: Once = Once::new();
+
+// the config is loaded from the environment the first time it's used,
+// so this needs to run before anything reads it
+pub fn init_config() {
+    INIT.call_once(|| {
+        let vars = [
+            ("pluralkit__db__data_db_uri", "postgresql://localhost/test"),
+            ("pluralkit__db__data_redis_addr", "redis://localhost:6379"),
+            ("pluralkit__discord__client_id", "1"),
+            ("pluralkit__discord__bot_token", "token"),
+            ("pluralkit__discord__client_secret", "secret"),
+            ("pluralkit__discord__max_concurrency", "1"),
+        ];
+        for (key, value) in vars {
+            // tests that need the config all go through here first
+            unsafe { std::env::set_var(key, value) };
+        }
+    });
+}
+
+// a pool that never connects, for things that need one but don't touch redis in the test
+pub fn redis() -> RedisPool {
+    RedisPool::new(RedisConfig::default(), None, None, None, 1).unwrap()
+}
+
+// runtime config with an event target set, so the bot's events get forwarded
+pub fn runtime_config() -> Arc<RuntimeConfig> {
+    Arc::new(RuntimeConfig::with_settings(
+        redis(),
+        "gateway".to_string(),
+        HashMap::from([(
+            RUNTIME_CONFIG_KEY_EVENT_TARGET.to_string(),
+            "http://localhost/events".to_string(),
+        )]),
+    ))
+}
+
+// serves a fake gateway playing this scenario, returns its url
+pub async fn fake_gateway(scenario: Value) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let app = fake_gateway::router(Settings {
+        scenario: serde_json::from_value::<Scenario>(scenario).unwrap(),
+        url: url.clone(),
+        user_id: "1".to_string(),
+        max_concurrency: 1,
+    });
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    url
+}
+
+// a scenario step sending a message in channel 10
+pub fn message_create(id: u64) -> Value {
+    json!({
+        "action": "dispatch",
+        "t": "MESSAGE_CREATE",
+        "d": {
+            "id": id.to_string(),
+            "channel_id": "10",
+            "content": "hello",
+            "author": { "id": "20", "username": "test", "discriminator": "0", "avatar": null },
+            "timestamp": "2025-01-01T00:00:00.000000+00:00",
+            "edited_timestamp": null,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": [],
+            "mention_roles": [],
+            "attachments": [],
+            "embeds": [],
+            "pinned": false,
+            "type": 0,
+        },
+    })
+}
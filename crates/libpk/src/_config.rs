@@ -36,6 +36,102 @@ pub struct DiscordConfig {
 
     #[serde(default)]
     pub gateway_proxy_url: Option<String>,
+
+    // also require `internal_auth` for reads from the cache api, not just for changes
+    #[serde(default)]
+    pub cache_api_auth_reads: bool,
+
+    // where to save the cache on shutdown, to restore it on the next start
+    #[serde(default)]
+    pub cache_snapshot_path: Option<String>,
+
+    // how many recent messages to keep per channel
+    // the default matches the old current + previous message; raise it for deeper history
+    #[serde(default = "_default_last_message_cache_size")]
+    pub last_message_cache_size: usize,
+
+    // events the bot couldn't take are spilled here, and replayed later
+    // if unset, they're dropped
+    #[serde(default)]
+    pub event_spool_dir: Option<String>,
+
+    #[serde(default = "_default_event_spool_max_bytes")]
+    pub event_spool_max_bytes: u64,
+
+    // seconds, spooled MESSAGE_CREATEs older than this are dropped instead of replayed
+    #[serde(default = "_default_event_spool_max_message_age")]
+    pub event_spool_max_message_age: u64,
+
+    // report event rates for this many of the busiest guilds, 0 to turn it off
+    // every guild that's ever been in the top k keeps a series until restart, see event_metrics.rs
+    #[serde(default)]
+    pub guild_metrics_top_k: usize,
+
+    // seconds to keep delivering queued events for on shutdown
+    #[serde(default = "_default_shutdown_drain_timeout")]
+    pub shutdown_drain_timeout: u64,
+
+    // max in-flight requests per event target
+    #[serde(default = "_default_event_target_concurrency")]
+    pub event_target_concurrency: usize,
+
+    #[serde(default = "_default_member_cache_size")]
+    pub member_cache_size: usize,
+
+    // seconds
+    #[serde(default = "_default_member_cache_ttl")]
+    pub member_cache_ttl: u64,
+
+    // optional resources for the cache api, off by default since they use a fair bit of memory
+    #[serde(default)]
+    pub cache_threads: bool,
+
+    #[serde(default)]
+    pub cache_emojis: bool,
+
+    #[serde(default)]
+    pub cache_stickers: bool,
+
+    // append every raw gateway payload to this file
+    #[serde(default)]
+    pub event_record_path: Option<String>,
+
+    // don't connect to discord, and run the payloads recorded in this file through the gateway instead
+    #[serde(default)]
+    pub event_replay_path: Option<String>,
+
+    // where replayed events for the bot go, instead of the usual event target
+    // if unset, replaying only updates the cache
+    #[serde(default)]
+    pub event_replay_target: Option<String>,
+}
+
+fn _default_last_message_cache_size() -> usize {
+    2
+}
+
+fn _default_event_spool_max_bytes() -> u64 {
+    256_000_000
+}
+
+fn _default_event_spool_max_message_age() -> u64 {
+    60
+}
+
+fn _default_shutdown_drain_timeout() -> u64 {
+    10
+}
+
+fn _default_event_target_concurrency() -> usize {
+    200
+}
+
+fn _default_member_cache_size() -> usize {
+    50_000
+}
+
+fn _default_member_cache_ttl() -> u64 {
+    300
 }
 
 #[derive(Deserialize, Debug)]
@@ -65,6 +161,24 @@ pub struct ApiConfig {
 
     #[serde(default)]
     pub temp_token2: Option<String>,
+
+    /// comma-separated, these (and registered apps' origins) can make credentialed requests
+    #[serde(default)]
+    pub cors_allowed_origins: Option<String>,
+
+    #[serde(default = "_default_cors_allowed_headers")]
+    pub cors_allowed_headers: String,
+
+    #[serde(default = "_default_cors_max_age")]
+    pub cors_max_age: u64,
+}
+
+fn _default_cors_allowed_headers() -> String {
+    "Content-Type, Authorization, sentry-trace, User-Agent".to_string()
+}
+
+fn _default_cors_max_age() -> u64 {
+    86400
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -103,6 +217,14 @@ pub struct ScheduledTasksConfig {
     pub prometheus_auth_token: Option<String>,
 
     pub walg_s3_bucket: String,
+
+    // api audit log entries older than this are deleted
+    #[serde(default = "_default_audit_log_retention_days")]
+    pub audit_log_retention_days: i32,
+}
+
+fn _default_audit_log_retention_days() -> i32 {
+    90
 }
 
 fn _metrics_default() -> bool {
@@ -1,4 +1,5 @@
 use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
 
 use crate::db::types::avatars::*;
 
@@ -109,3 +110,12 @@ pub async fn push_queue(
         .await?;
     Ok(())
 }
+
+pub async fn get_by_system(pool: &PgPool, system_uuid: Uuid) -> anyhow::Result<Vec<ImageMeta>> {
+    Ok(sqlx::query_as(
+        "select * from images where uploaded_by_system = $1 order by uploaded_at desc",
+    )
+    .bind(system_uuid)
+    .fetch_all(pool)
+    .await?)
+}
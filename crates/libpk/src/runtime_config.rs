@@ -11,19 +11,26 @@ pub struct RuntimeConfig {
 
 impl RuntimeConfig {
     pub async fn new(redis: RedisPool, component_key: String) -> anyhow::Result<Self> {
-        let redis_key = format!("remote_config:{component_key}");
-
-        let mut c = RuntimeConfig {
-            redis,
-            settings: RwLock::new(HashMap::new()),
-            redis_key,
-        };
+        let mut c = Self::with_settings(redis, component_key, HashMap::new());
 
         c.load().await?;
 
         Ok(c)
     }
 
+    /// starts with these settings, without loading anything from redis
+    pub fn with_settings(
+        redis: RedisPool,
+        component_key: String,
+        settings: HashMap<String, String>,
+    ) -> Self {
+        RuntimeConfig {
+            redis,
+            settings: RwLock::new(settings),
+            redis_key: format!("remote_config:{component_key}"),
+        }
+    }
+
     pub async fn load(&mut self) -> anyhow::Result<()> {
         let redis_config: HashMap<String, String> = self.redis.hgetall(&self.redis_key).await?;
 
@@ -13,8 +13,186 @@ pub struct ShardState {
 }
 
 pub enum ShardStateEvent {
-    Closed,
+    /// with the close code, if we got one
+    Closed(Option<u16>),
     Heartbeat,
-    Reconnect,
+    Reconnect(Option<u16>),
     Other,
 }
+
+/// how long shard history is kept for
+pub const SHARD_HISTORY_RETENTION: i64 = 30 * 24 * 60 * 60;
+
+/// sorted set of [`ShardTransition`]s, scored by timestamp
+pub fn shard_transitions_key(shard_id: i32) -> String {
+    format!("pluralkit:shardstatus:transitions:{shard_id}")
+}
+
+/// sorted set of [`ShardLatencySample`]s, scored by timestamp
+pub fn shard_latency_key(shard_id: i32) -> String {
+    format!("pluralkit:shardstatus:latency:{shard_id}")
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardTransitionKind {
+    Ready,
+    Resumed,
+    Down,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ShardTransition {
+    /// unix timestamp
+    pub timestamp: i64,
+    pub kind: ShardTransitionKind,
+    pub close_code: Option<u16>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ShardLatencySample {
+    /// unix timestamp
+    pub timestamp: i64,
+    /// milliseconds
+    pub latency: i32,
+}
+
+/// percentage of time between `start` and `end` the shard was up
+///
+/// `transitions` should be sorted, and include the last one before `start`
+/// time before the first transition isn't counted either way; None if nothing is known
+pub fn shard_uptime(transitions: &[ShardTransition], start: i64, end: i64) -> Option<f64> {
+    let mut up: Option<bool> = None;
+    let mut since = start;
+    let mut up_secs = 0;
+    let mut known_secs = 0;
+
+    for transition in transitions {
+        let is_up = !matches!(transition.kind, ShardTransitionKind::Down);
+        if transition.timestamp <= start {
+            up = Some(is_up);
+            continue;
+        }
+        if transition.timestamp > end {
+            break;
+        }
+
+        if let Some(was_up) = up {
+            let secs = transition.timestamp - since;
+            known_secs += secs;
+            if was_up {
+                up_secs += secs;
+            }
+        }
+        up = Some(is_up);
+        since = transition.timestamp;
+    }
+
+    if let Some(was_up) = up {
+        let secs = end - since;
+        known_secs += secs;
+        if was_up {
+            up_secs += secs;
+        }
+    }
+
+    (known_secs > 0).then(|| up_secs as f64 / known_secs as f64 * 100.0)
+}
+
+/// the Down a shard should have gotten, if the gateway stopped without recording one
+///
+/// a crashed gateway leaves the shard's last transition up, so it's counted as down
+/// from when it was last seen: its last heartbeat, or its connection if it never heartbeated
+pub fn missed_down(last: &ShardTransition, state: &ShardState) -> Option<ShardTransition> {
+    if matches!(last.kind, ShardTransitionKind::Down) {
+        return None;
+    }
+    Some(ShardTransition {
+        timestamp: last
+            .timestamp
+            .max(state.last_heartbeat as i64)
+            .max(state.last_connection as i64),
+        kind: ShardTransitionKind::Down,
+        close_code: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transition(timestamp: i64, kind: ShardTransitionKind) -> ShardTransition {
+        ShardTransition {
+            timestamp,
+            kind,
+            close_code: None,
+        }
+    }
+
+    #[test]
+    fn uptime_with_state_from_before_window() {
+        let transitions = [
+            transition(50, ShardTransitionKind::Ready),
+            transition(150, ShardTransitionKind::Down),
+        ];
+        assert_eq!(shard_uptime(&transitions, 100, 200), Some(50.0));
+    }
+
+    #[test]
+    fn uptime_with_transition_at_start() {
+        // a transition exactly at `start` decides the state the window starts in
+        let transitions = [
+            transition(100, ShardTransitionKind::Down),
+            transition(150, ShardTransitionKind::Resumed),
+        ];
+        assert_eq!(shard_uptime(&transitions, 100, 200), Some(50.0));
+    }
+
+    #[test]
+    fn uptime_ignores_time_before_first_transition() {
+        let transitions = [transition(150, ShardTransitionKind::Ready)];
+        assert_eq!(shard_uptime(&transitions, 100, 200), Some(100.0));
+    }
+
+    #[test]
+    fn uptime_ignores_transitions_after_end() {
+        let transitions = [
+            transition(50, ShardTransitionKind::Ready),
+            transition(250, ShardTransitionKind::Down),
+        ];
+        assert_eq!(shard_uptime(&transitions, 100, 200), Some(100.0));
+    }
+
+    #[test]
+    fn uptime_unknown_without_transitions() {
+        assert_eq!(shard_uptime(&[], 100, 200), None);
+    }
+
+    #[test]
+    fn uptime_unknown_for_empty_window() {
+        let transitions = [transition(50, ShardTransitionKind::Ready)];
+        assert_eq!(shard_uptime(&transitions, 100, 100), None);
+    }
+
+    #[test]
+    fn uptime_counts_crashes_from_last_heartbeat() {
+        let ready = transition(0, ShardTransitionKind::Ready);
+        let state = ShardState {
+            last_connection: 0,
+            last_heartbeat: 50,
+            ..Default::default()
+        };
+        let down = missed_down(&ready, &state).unwrap();
+        assert_eq!(down.timestamp, 50);
+
+        // the gateway came back at 100
+        let transitions = [ready, down, transition(100, ShardTransitionKind::Ready)];
+        assert_eq!(shard_uptime(&transitions, 0, 200), Some(75.0));
+    }
+
+    #[test]
+    fn no_missed_down_after_a_clean_stop() {
+        let down = transition(50, ShardTransitionKind::Down);
+        assert!(missed_down(&down, &ShardState::default()).is_none());
+    }
+}
@@ -0,0 +1,23 @@
+-- database version 54
+-- add audit log for changes made through the API
+
+create table audit_log (
+    id bigserial primary key,
+    system int not null references systems(id) on delete cascade,
+    app_id int,
+    -- legacy tokens don't have ids, so this is a hash of the token (see the api's auth middleware)
+    token_id text,
+    method text not null,
+    route text not null,
+    entity_type text,
+    entity_uuid uuid,
+    request jsonb,
+    diff jsonb,
+    created timestamp not null default (current_timestamp at time zone 'utc')
+);
+
+create index audit_log_system_idx on audit_log (system, id);
+-- for purging old entries
+create index audit_log_created_idx on audit_log (created);
+
+update info set schema_version = 54;
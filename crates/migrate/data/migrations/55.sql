@@ -0,0 +1,11 @@
+-- database version 55
+-- add registered api apps, and the web origins they're allowed to make credentialed requests from
+
+create table api_apps (
+    id serial primary key,
+    name text not null,
+    web_origins text[] not null default array[]::text[],
+    created timestamp not null default (current_timestamp at time zone 'utc')
+);
+
+update info set schema_version = 55;
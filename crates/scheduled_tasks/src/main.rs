@@ -93,6 +93,8 @@ async fn main() -> anyhow::Result<()> {
         "queue deleted image cleanup job",
         queue_deleted_image_cleanup
     );
+    // every day at 04:00
+    doforever!("0 4 * * *", "audit log cleanup job", cleanup_audit_log);
     // non-standard cron: at hh:mm:00, hh:mm:30
     doforever!("0,30 * * * * *", "stats api updater", update_stats_api);
     // every hour (could probably even be less frequent, basebackups are taken rarely)
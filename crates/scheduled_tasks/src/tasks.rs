@@ -200,7 +200,11 @@ pub async fn update_discord_stats(ctx: AppCtx) -> anyhow::Result<()> {
             url = url.replace("{clusterid}", &idx.to_string());
         }
 
-        let res = client.get(&url).send().await?;
+        let mut req = client.get(&url);
+        if let Some(token) = libpk::config.internal_auth.as_ref() {
+            req = req.header("x-pluralkit-internalauth", token);
+        }
+        let res = req.send().await?;
 
         let stat: GatewayStatus = res.json().await?;
 
@@ -288,6 +292,24 @@ pub async fn queue_deleted_image_cleanup(ctx: AppCtx) -> anyhow::Result<()> {
     Ok(())
 }
 
+pub async fn cleanup_audit_log(ctx: AppCtx) -> anyhow::Result<()> {
+    let cfg = config
+        .scheduled_tasks
+        .as_ref()
+        .expect("missing scheduled_tasks config");
+
+    let deleted = sqlx::query(
+        "delete from audit_log where created < (current_timestamp at time zone 'utc') - make_interval(days => $1)",
+    )
+    .bind(cfg.audit_log_retention_days)
+    .execute(&ctx.data)
+    .await?
+    .rows_affected();
+    tracing::info!(deleted, "cleaned up old audit log entries");
+
+    Ok(())
+}
+
 pub async fn update_stats_api(ctx: AppCtx) -> anyhow::Result<()> {
     let client = ClientBuilder::new()
         .connect_timeout(Duration::from_secs(3))